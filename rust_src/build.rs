@@ -1,14 +1,251 @@
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const VENDORED_FFMPEG_VERSION: &str = "6.1.1";
+
+/// An FFmpeg library that's only linked/bound when the matching Cargo
+/// feature is enabled, following the pattern ffmpeg-sys-style crates use to
+/// keep audio-only consumers from pulling in the video libs.
+struct OptionalLib {
+    pkg_config_name: &'static str,
+    feature: &'static str,
+    fn_allowlist: &'static [&'static str],
+    type_allowlist: &'static [&'static str],
+}
+
+// Note: libavfilter is deliberately NOT in this table. `processor.rs` uses a
+// handful of its symbols (`avfilter_graph_*`, `av_buffersrc_add_frame`,
+// `av_buffersink_get_frame`) unconditionally on every task for loudness
+// measurement, so gating them behind a Cargo feature here would break a
+// default `cargo build`. They're declared by hand in `ffmpeg.rs` instead.
+const OPTIONAL_LIBS: &[OptionalLib] = &[
+    OptionalLib {
+        pkg_config_name: "libavdevice",
+        feature: "device",
+        fn_allowlist: &["avdevice_.*"],
+        type_allowlist: &[],
+    },
+    OptionalLib {
+        pkg_config_name: "libswscale",
+        feature: "swscale",
+        fn_allowlist: &["sws_.*"],
+        type_allowlist: &["SwsContext"],
+    },
+    OptionalLib {
+        pkg_config_name: "libpostproc",
+        feature: "postproc",
+        fn_allowlist: &["pp_.*"],
+        type_allowlist: &[],
+    },
+];
+
+/// Fixes two rough edges bindgen otherwise leaves in the generated
+/// `bindings.rs`: macro families that get inconsistent/too-wide integer
+/// types, and enum variants that stutter their enum name (e.g.
+/// `AVSampleFormat_AV_SAMPLE_FMT_FLT`).
+#[derive(Debug)]
+struct Callbacks;
+
+impl bindgen::callbacks::ParseCallbacks for Callbacks {
+    fn int_macro(&self, name: &str, _value: i64) -> Option<bindgen::callbacks::IntKind> {
+        if name.starts_with("AV_CODEC_CAP") || name.starts_with("AV_CODEC_FLAG") || name.starts_with("AV_CH") {
+            Some(bindgen::callbacks::IntKind::UInt)
+        } else if name.starts_with("AVERROR") || name == "EAGAIN" {
+            Some(bindgen::callbacks::IntKind::Int)
+        } else {
+            None
+        }
+    }
+
+    fn item_name(&self, original_item_name: &str) -> Option<String> {
+        // bindgen concatenates "{EnumName}_{VARIANT}" for consts-style enums,
+        // which is redundant when the C macro family already encodes the
+        // enum's identity (AV_SAMPLE_FMT_*, AVMEDIA_TYPE_*, AV_ROUND_*,
+        // AV_CODEC_ID_*). Drop the duplicated enum-name prefix so call sites
+        // read as plain FFmpeg constant names.
+        for prefix in ["AVSampleFormat_", "AVMediaType_", "AVRounding_", "AVCodecID_"] {
+            if let Some(stripped) = original_item_name.strip_prefix(prefix) {
+                return Some(stripped.to_string());
+            }
+        }
+        None
+    }
+}
+
+/// Parses the major component out of a pkg-config version string (e.g.
+/// libavcodec's `"60.31.102"`). This is the *library* soname version, not
+/// FFmpeg's release number: libavcodec/libavformat 58, 59, 60 and 61 ship
+/// with FFmpeg 4, 5, 6 and 7. That is enough to gate the handful of
+/// signature changes (`AVCodec` constness, `AVChannelLayout` replacing the
+/// old `uint64_t` bitmask, `avformat_open_input` vs `av_open_input_file`)
+/// that differ between FFmpeg 4 through 7.
+fn lib_major_version(lib: &pkg_config::Library) -> u32 {
+    lib.version
+        .split('.')
+        .next()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Builds FFmpeg from source into `$OUT_DIR/ffmpeg-vendor` and returns that
+/// install prefix, for systems without FFmpeg dev packages installed.
+/// Memoized on the prefix's `lib/pkgconfig` directory already existing, so
+/// repeat builds (incremental rebuilds, multiple libs falling back in the
+/// same `cargo build`) don't redo the configure/make cycle.
+fn build_vendored_ffmpeg(out_dir: &Path) -> PathBuf {
+    let install_prefix = out_dir.join("ffmpeg-vendor");
+    if install_prefix.join("lib").join("pkgconfig").exists() {
+        return install_prefix;
+    }
+
+    let src_dir = out_dir.join(format!("ffmpeg-{}", VENDORED_FFMPEG_VERSION));
+    if !src_dir.join("configure").exists() {
+        let tarball = out_dir.join("ffmpeg.tar.bz2");
+        let url = format!(
+            "https://ffmpeg.org/releases/ffmpeg-{}.tar.bz2",
+            VENDORED_FFMPEG_VERSION
+        );
+        let status = Command::new("curl")
+            .args(["-L", "-sS", "-o"])
+            .arg(&tarball)
+            .arg(&url)
+            .status()
+            .expect("curl not found (required to fetch the vendored FFmpeg source)");
+        assert!(status.success(), "failed to download {}", url);
+
+        let status = Command::new("tar")
+            .arg("-xjf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(out_dir)
+            .status()
+            .expect("tar not found (required to extract the vendored FFmpeg source)");
+        assert!(status.success(), "failed to extract {}", tarball.display());
+    }
+
+    let status = Command::new("./configure")
+        .current_dir(&src_dir)
+        .arg(format!("--prefix={}", install_prefix.display()))
+        .args([
+            "--disable-programs",
+            "--disable-doc",
+            // Deliberately NOT --disable-network: remote-URL input relies on
+            // FFmpeg's http/https/rtmp/tcp protocols, and the vendored build
+            // is the fallback path for exactly the systems where those might
+            // otherwise be missing from a system pkg-config install.
+            "--enable-static",
+            "--disable-shared",
+            "--enable-pic",
+        ])
+        .status()
+        .expect("failed to run FFmpeg's configure script");
+    assert!(status.success(), "FFmpeg configure failed");
+
+    let jobs = std::thread::available_parallelism().map(|p| p.get()).unwrap_or(4);
+    let status = Command::new("make")
+        .current_dir(&src_dir)
+        .arg(format!("-j{}", jobs))
+        .arg("install")
+        .status()
+        .expect("failed to run make");
+    assert!(status.success(), "FFmpeg build failed");
+
+    install_prefix
+}
+
+/// Probes `name` via pkg-config, falling back to a from-source build when
+/// pkg-config can't find it (or when `vendored` forces that path), mirroring
+/// how build scripts for vendored libs prepend their own install prefix to
+/// `PKG_CONFIG_PATH` before re-probing.
+fn probe_library_or_vendor(name: &str, out_dir: &Path, vendored: bool) -> pkg_config::Library {
+    if !vendored {
+        if let Ok(lib) = pkg_config::probe_library(name) {
+            return lib;
+        }
+    }
+
+    let install_prefix = build_vendored_ffmpeg(out_dir);
+    let pkgconfig_dir = install_prefix.join("lib").join("pkgconfig");
+
+    let existing = env::var("PKG_CONFIG_PATH").unwrap_or_default();
+    let combined = if existing.is_empty() {
+        pkgconfig_dir.display().to_string()
+    } else {
+        format!("{}:{}", pkgconfig_dir.display(), existing)
+    };
+    env::set_var("PKG_CONFIG_PATH", &combined);
+
+    // Probe without cargo metadata: the vendored libs are static archives, so
+    // we emit our own `rustc-link-lib=static=...` below rather than letting
+    // pkg-config's default dynamic `-l` directives reach the linker.
+    let probed = pkg_config::Config::new()
+        .cargo_metadata(false)
+        .probe(name)
+        .unwrap_or_else(|e| {
+            panic!(
+                "vendored build of {} didn't produce a usable pkg-config file: {}",
+                name, e
+            )
+        });
+
+    println!("cargo:rustc-link-search=native={}", install_prefix.join("lib").display());
+    for lib_name in &probed.libs {
+        println!("cargo:rustc-link-lib=static={}", lib_name);
+    }
+
+    probed
+}
 
 fn main() {
     println!("cargo:rerun-if-changed=build.rs");
 
-    // Use pkg-config to find FFmpeg libraries
-    let avcodec = pkg_config::probe_library("libavcodec").expect("libavcodec not found");
-    let avformat = pkg_config::probe_library("libavformat").expect("libavformat not found");
-    let avutil = pkg_config::probe_library("libavutil").expect("libavutil not found");
-    let swresample = pkg_config::probe_library("libswresample").expect("libswresample not found");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let vendored = env::var_os("CARGO_FEATURE_BUILD_VENDORED").is_some();
+
+    // Core libraries are always required: resampling-only consumers still
+    // need decode/encode/demux/mux. Each falls back to a from-source build
+    // when pkg-config can't find it, or unconditionally under the
+    // `build-vendored` feature for a reproducible, self-contained build.
+    let avcodec = probe_library_or_vendor("libavcodec", &out_dir, vendored);
+    let avformat = probe_library_or_vendor("libavformat", &out_dir, vendored);
+    let avutil = probe_library_or_vendor("libavutil", &out_dir, vendored);
+    let swresample = probe_library_or_vendor("libswresample", &out_dir, vendored);
+    // Linked for the hand-declared filter-graph symbols in `ffmpeg.rs`; not
+    // run through bindgen.
+    probe_library_or_vendor("libavfilter", &out_dir, vendored);
+
+    for (lib_name, lib) in [
+        ("AVCODEC", &avcodec),
+        ("AVFORMAT", &avformat),
+        ("AVUTIL", &avutil),
+        ("SWRESAMPLE", &swresample),
+    ] {
+        println!("cargo:rustc-env=FFMPEG_{}_VERSION={}", lib_name, lib.version);
+    }
+
+    // Let the rest of the crate #[cfg]-switch on the FFmpeg major version
+    // instead of failing to compile against whatever the user has
+    // installed. The crate's current FFI already assumes the `AVCodec`
+    // pointers it gets back are `const` and that `AVChannelLayout` exists,
+    // i.e. FFmpeg 5+; these cfgs are the hook future version-specific code
+    // paths (e.g. a legacy `av_open_input_file` fallback) would switch on.
+    let avcodec_major = lib_major_version(&avcodec);
+    let avformat_major = lib_major_version(&avformat);
+
+    println!("cargo:rustc-cfg=ffmpeg_{}", avcodec_major);
+    // Soname majors 58..=62, i.e. FFmpeg 4 through 8.
+    for major in 58..=62 {
+        println!("cargo:rustc-check-cfg=cfg(ffmpeg_{})", major);
+        println!("cargo:rustc-check-cfg=cfg(ffmpeg_avformat_{})", major);
+    }
+    println!("cargo:rustc-cfg=ffmpeg_avformat_{}", avformat_major);
+
+    println!("cargo:rustc-check-cfg=cfg(ffmpeg_const_avcodec)");
+    // libavcodec 59 is FFmpeg 5, where `AVCodec` pointers became `const`.
+    if avcodec_major >= 59 {
+        println!("cargo:rustc-cfg=ffmpeg_const_avcodec");
+    }
 
     // Collect include paths for bindgen
     let mut clang_args: Vec<String> = Vec::new();
@@ -46,7 +283,10 @@ fn main() {
         .allowlist_type("AVSampleFormat")
         .allowlist_type("AVCodecID")
         .allowlist_type("AVRounding")
+        .allowlist_type("AVDictionary")
+        .allowlist_type("AVDictionaryEntry")
         .allowlist_var("AVMEDIA_TYPE_.*")
+        .allowlist_var("AV_DICT_.*")
         .allowlist_var("AV_SAMPLE_FMT_.*")
         .allowlist_var("AV_CODEC_ID_.*")
         .allowlist_var("AV_CODEC_FLAG_.*")
@@ -55,16 +295,39 @@ fn main() {
         .allowlist_var("AV_ROUND_.*")
         .allowlist_var("AVERROR.*")
         .allowlist_var("EAGAIN")
+        .parse_callbacks(Box::new(Callbacks))
         .derive_default(true);
 
+    // Optional libraries only get probed, linked, and allowlisted when the
+    // matching `CARGO_FEATURE_<NAME>` is set, i.e. the crate feature of the
+    // same name is enabled.
+    for lib in OPTIONAL_LIBS {
+        let feature_env = format!("CARGO_FEATURE_{}", lib.feature.to_uppercase());
+        if env::var_os(&feature_env).is_none() {
+            continue;
+        }
+
+        let probed = probe_library_or_vendor(lib.pkg_config_name, &out_dir, vendored);
+
+        for path in &probed.include_paths {
+            clang_args.push(format!("-I{}", path.display()));
+        }
+
+        for pattern in lib.fn_allowlist {
+            builder = builder.allowlist_function(*pattern);
+        }
+        for pattern in lib.type_allowlist {
+            builder = builder.allowlist_type(*pattern);
+        }
+    }
+
     for arg in &clang_args {
         builder = builder.clang_arg(arg);
     }
 
     let bindings = builder.generate().expect("Unable to generate bindings");
 
-    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap());
     bindings
-        .write_to_file(out_path.join("bindings.rs"))
+        .write_to_file(out_dir.join("bindings.rs"))
         .expect("Couldn't write bindings!");
 }
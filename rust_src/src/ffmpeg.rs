@@ -0,0 +1,61 @@
+#![allow(non_camel_case_types, non_snake_case, non_upper_case_globals, dead_code)]
+
+include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+// libavfilter is linked by build.rs but isn't part of its bindgen allowlist
+// (only avcodec/avformat/avutil/swresample headers are bound), but
+// `measure_file_inner` builds an ebur128 filter graph unconditionally on
+// every task (not just when `--filter-chain`/`--normalize` is passed), so
+// gating these symbols behind a Cargo feature isn't an option without also
+// making loudness measurement itself optional. The handful of filter-graph
+// symbols actually used are declared by hand here instead.
+extern "C" {
+    pub fn avfilter_graph_alloc() -> *mut AVFilterGraph;
+    pub fn avfilter_graph_free(graph: *mut *mut AVFilterGraph);
+    pub fn avfilter_graph_create_filter(
+        filt_ctx: *mut *mut AVFilterContext,
+        filt: *const AVFilter,
+        name: *const std::os::raw::c_char,
+        args: *const std::os::raw::c_char,
+        opaque: *mut std::os::raw::c_void,
+        graph_ctx: *mut AVFilterGraph,
+    ) -> i32;
+    pub fn avfilter_graph_parse_ptr(
+        graph: *mut AVFilterGraph,
+        filters: *const std::os::raw::c_char,
+        inputs: *mut *mut AVFilterInOut,
+        outputs: *mut *mut AVFilterInOut,
+        log_ctx: *mut std::os::raw::c_void,
+    ) -> i32;
+    pub fn avfilter_graph_config(graph: *mut AVFilterGraph, log_ctx: *mut std::os::raw::c_void) -> i32;
+    pub fn avfilter_inout_alloc() -> *mut AVFilterInOut;
+    pub fn avfilter_inout_free(inout: *mut *mut AVFilterInOut);
+    pub fn avfilter_get_by_name(name: *const std::os::raw::c_char) -> *const AVFilter;
+
+    pub fn av_buffersrc_add_frame(ctx: *mut AVFilterContext, frame: *mut AVFrame) -> i32;
+    pub fn av_buffersink_get_frame(ctx: *mut AVFilterContext, frame: *mut AVFrame) -> i32;
+}
+
+#[repr(C)]
+pub struct AVFilterGraph {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+pub struct AVFilterContext {
+    pub name: *mut std::os::raw::c_char,
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+pub struct AVFilter {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+pub struct AVFilterInOut {
+    pub name: *mut std::os::raw::c_char,
+    pub filter_ctx: *mut AVFilterContext,
+    pub pad_idx: i32,
+    pub next: *mut AVFilterInOut,
+}
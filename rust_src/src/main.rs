@@ -5,6 +5,7 @@ use rayon::prelude::*;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 use walkdir::WalkDir;
 
 struct Config {
@@ -15,10 +16,77 @@ struct Config {
     max_duration: f32,
     threads: usize,
     use_fork: bool,
+    segment: bool,
+    filter_chain: Option<String>,
+    output_codec: String,
+    normalize: bool,
+    io_timeout_ms: u64,
+    decoder_options: Vec<(String, String)>,
+}
+
+/// One entry per output file in `manifest.json`, giving ML users the
+/// provenance needed to reproduce a dataset (loudness stats, whether a
+/// clip was truncated or silence-padded).
+struct ManifestEntry {
+    input_path: String,
+    output_path: String,
+    input_sample_rate: u32,
+    output_sample_rate: u32,
+    measured_i: f64,
+    measured_tp: f64,
+    measured_lra: f64,
+    duration_sec: f32,
+    truncated: bool,
+    padded: bool,
+}
+
+fn json_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod json_escape_tests {
+    use super::*;
+
+    #[test]
+    fn escapes_backslashes_and_quotes() {
+        assert_eq!(json_escape(r#"C:\clips\"loud".wav"#), r#"C:\\clips\\\"loud\".wav"#);
+    }
+
+    #[test]
+    fn leaves_plain_paths_untouched() {
+        assert_eq!(json_escape("/data/clips/ep1.wav"), "/data/clips/ep1.wav");
+    }
+}
+
+fn write_manifest(output_dir: &str, entries: &[ManifestEntry]) -> std::io::Result<()> {
+    let mut json = String::from("[\n");
+    for (i, entry) in entries.iter().enumerate() {
+        json.push_str(&format!(
+            "  {{\"input_path\": \"{}\", \"output_path\": \"{}\", \"input_sample_rate\": {}, \"output_sample_rate\": {}, \"measured_i\": {}, \"measured_tp\": {}, \"measured_lra\": {}, \"duration_sec\": {}, \"truncated\": {}, \"padded\": {}}}",
+            json_escape(&entry.input_path),
+            json_escape(&entry.output_path),
+            entry.input_sample_rate,
+            entry.output_sample_rate,
+            entry.measured_i,
+            entry.measured_tp,
+            entry.measured_lra,
+            entry.duration_sec,
+            entry.truncated,
+            entry.padded,
+        ));
+        json.push_str(if i + 1 < entries.len() { ",\n" } else { "\n" });
+    }
+    json.push_str("]\n");
+
+    std::fs::write(Path::new(output_dir).join("manifest.json"), json)
 }
 
 struct Task {
     input_path: PathBuf,
+    // Base path for this task's output. In segment mode a single task fans
+    // out into several `<stem>_000.wav`, `<stem>_001.wav`, ... files rather
+    // than a single output, so this is a one-to-many mapping in that case.
     output_path: PathBuf,
 }
 
@@ -30,9 +98,80 @@ fn is_audio_file(path: &Path) -> bool {
         .unwrap_or(false)
 }
 
-fn collect_tasks(input_dir: &str, output_dir: &str) -> Vec<Task> {
+fn is_url(s: &str) -> bool {
+    ["http://", "https://", "rtmp://"]
+        .iter()
+        .any(|scheme| s.starts_with(scheme))
+}
+
+/// Derives an output path under `output_dir` from a URL's basename, e.g.
+/// `https://example.com/clips/ep1.mp3` -> `<output_dir>/ep1.<ext>`.
+fn output_path_for_url(url: &str, output_dir: &str, extension: &str) -> PathBuf {
+    let basename = url
+        .rsplit('/')
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("remote");
+    let stem = Path::new(basename)
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or(basename);
+    Path::new(output_dir).join(format!("{}.{}", stem, extension))
+}
+
+#[cfg(test)]
+mod output_path_for_url_tests {
+    use super::*;
+
+    #[test]
+    fn derives_output_path_from_url_basename() {
+        assert_eq!(
+            output_path_for_url("https://example.com/clips/ep1.mp3", "/out", "wav"),
+            PathBuf::from("/out/ep1.wav")
+        );
+    }
+
+    #[test]
+    fn falls_back_to_remote_for_a_trailing_slash() {
+        assert_eq!(
+            output_path_for_url("https://example.com/clips/", "/out", "wav"),
+            PathBuf::from("/out/remote.wav")
+        );
+    }
+}
+
+fn collect_remote_tasks(urls: &[String], output_dir: &str, extension: &str) -> Vec<Task> {
+    urls.iter()
+        .map(|url| Task {
+            input_path: PathBuf::from(url),
+            output_path: output_path_for_url(url, output_dir, extension),
+        })
+        .collect()
+}
+
+fn collect_tasks(input_dir: &str, output_dir: &str, output_codec: &str) -> Vec<Task> {
     let input_path = Path::new(input_dir);
     let output_path = Path::new(output_dir);
+    let (_, extension) = processor::container_for_codec(output_codec);
+
+    // A direct remote source, or a plain-text index file listing one URL
+    // per line: both skip the local directory walk entirely.
+    if is_url(input_dir) {
+        return collect_remote_tasks(&[input_dir.to_string()], output_dir, extension);
+    }
+
+    if input_path.is_file() {
+        if let Ok(contents) = std::fs::read_to_string(input_path) {
+            let urls: Vec<String> = contents
+                .lines()
+                .map(|l| l.trim().to_string())
+                .filter(|l| !l.is_empty() && is_url(l))
+                .collect();
+            if !urls.is_empty() {
+                return collect_remote_tasks(&urls, output_dir, extension);
+            }
+        }
+    }
 
     WalkDir::new(input_dir)
         .into_iter()
@@ -41,7 +180,7 @@ fn collect_tasks(input_dir: &str, output_dir: &str) -> Vec<Task> {
         .map(|entry| {
             let rel_path = entry.path().strip_prefix(input_path).unwrap();
             let mut out_file = output_path.join(rel_path);
-            out_file.set_extension("wav");
+            out_file.set_extension(extension);
 
             Task {
                 input_path: entry.path().to_path_buf(),
@@ -62,13 +201,16 @@ fn ensure_output_dirs(tasks: &[Task]) {
     }
 }
 
-fn process_file_fork(task: &Task, config: &processor::ProcessorConfig) -> Result<(), String> {
-    let duration_output = Command::new("ffprobe")
+fn process_file_fork(
+    task: &Task,
+    config: &processor::ProcessorConfig,
+) -> Result<processor::ProcessOutcome, String> {
+    let probe_output = Command::new("ffprobe")
         .args([
             "-v",
             "error",
             "-show_entries",
-            "format=duration",
+            "format=duration:stream=sample_rate",
             "-of",
             "default=noprint_wrappers=1:nokey=1",
             task.input_path.to_str().unwrap(),
@@ -76,24 +218,108 @@ fn process_file_fork(task: &Task, config: &processor::ProcessorConfig) -> Result
         .output()
         .map_err(|e| format!("ffprobe failed: {}", e))?;
 
-    let duration: f32 = String::from_utf8_lossy(&duration_output.stdout)
-        .trim()
-        .parse()
-        .unwrap_or(0.0);
+    let mut probe_lines = String::from_utf8_lossy(&probe_output.stdout)
+        .lines()
+        .map(|l| l.trim().to_string())
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let input_sample_rate: u32 = probe_lines.next().and_then(|l| l.parse().ok()).unwrap_or(0);
+    let duration: f32 = probe_lines.next().and_then(|l| l.parse().ok()).unwrap_or(0.0);
 
-    let filter = format!(
+    // Mirrors process_file_inner's ordering: an optional libavfilter chain
+    // (e.g. --filter-chain, or --normalize's loudnorm) runs at the decoded
+    // sample rate, ahead of the resample to target_sample_rate.
+    let resample_filter = format!(
         "aresample={}:filter_size=64:cutoff=0.97",
         config.target_sample_rate
     );
+    let filter = match &config.filter_chain {
+        Some(chain) => format!("{},{}", chain, resample_filter),
+        None => resample_filter,
+    };
 
     let mut cmd = Command::new("ffmpeg");
     cmd.args(["-y", "-v", "error", "-i", task.input_path.to_str().unwrap()]);
 
-    if duration > config.max_duration_sec {
+    if config.segment {
+        // Mirrors the rollover done natively by process_file_inner: slice
+        // into consecutive max_duration_sec clips via ffmpeg's segment muxer
+        // instead of truncating. The min-duration pad is applied as a
+        // post-pass below, to just the trailing clip -- applying it here,
+        // ahead of the muxer, would pad the whole input's duration instead
+        // of the final short segment.
+        cmd.args(["-af", &filter]);
+        cmd.args([
+            "-ar",
+            &config.target_sample_rate.to_string(),
+            "-c:a",
+            &config.output_codec,
+            "-f",
+            "segment",
+            "-segment_time",
+            &config.max_duration_sec.to_string(),
+            "-reset_timestamps",
+            "1",
+        ]);
+
+        let stem = task
+            .output_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("out");
+        let ext = task
+            .output_path
+            .extension()
+            .and_then(|s| s.to_str())
+            .unwrap_or("wav");
+        let pattern = task
+            .output_path
+            .with_file_name(format!("{}_%03d.{}", stem, ext));
+        cmd.arg(pattern.to_str().unwrap());
+
+        let status = cmd.status().map_err(|e| format!("ffmpeg failed: {}", e))?;
+        return if status.success() {
+            let output_paths = segment_output_paths(&task.output_path, stem, ext);
+            let last_padded = match output_paths.last() {
+                Some(last) => pad_short_segment_in_place(last, config.min_duration_sec)?,
+                None => false,
+            };
+            // Every clip but the trailing one is always exactly
+            // max_duration_sec long and never truncated or padded; only the
+            // trailing clip's real length/padded state is re-probed below.
+            let last_index = output_paths.len().saturating_sub(1);
+            let outputs = output_paths
+                .iter()
+                .enumerate()
+                .map(|(i, path)| processor::OutputFile {
+                    path: path.clone(),
+                    duration_sec: if i == last_index {
+                        probe_duration_sec(path)
+                    } else {
+                        config.max_duration_sec
+                    },
+                    truncated: false,
+                    padded: i == last_index && last_padded,
+                })
+                .collect();
+            Ok(processor::ProcessOutcome {
+                input_sample_rate,
+                outputs,
+            })
+        } else {
+            Err("ffmpeg returned non-zero".to_string())
+        };
+    }
+
+    let truncated = duration > config.max_duration_sec;
+    let padded = duration < config.min_duration_sec;
+
+    if truncated {
         cmd.args(["-t", &config.max_duration_sec.to_string()]);
     }
 
-    if duration < config.min_duration_sec {
+    if padded {
         let pad_filter = format!("{},apad=whole_dur={}", filter, config.min_duration_sec);
         cmd.args(["-af", &pad_filter]);
     } else {
@@ -104,19 +330,154 @@ fn process_file_fork(task: &Task, config: &processor::ProcessorConfig) -> Result
         "-ar",
         &config.target_sample_rate.to_string(),
         "-c:a",
-        "pcm_f32le",
+        &config.output_codec,
         task.output_path.to_str().unwrap(),
     ]);
 
     let status = cmd.status().map_err(|e| format!("ffmpeg failed: {}", e))?;
 
     if status.success() {
-        Ok(())
+        Ok(processor::ProcessOutcome {
+            input_sample_rate,
+            outputs: vec![processor::OutputFile {
+                path: task.output_path.to_string_lossy().into_owned(),
+                duration_sec: duration.min(config.max_duration_sec).max(config.min_duration_sec),
+                truncated,
+                padded,
+            }],
+        })
     } else {
         Err("ffmpeg returned non-zero".to_string())
     }
 }
 
+/// Enumerates the `stem_000.ext`, `stem_001.ext`, ... clips that ffmpeg's
+/// `-f segment` muxer actually wrote next to `output_path`, since segmenting
+/// fans one task out into an a-priori-unknown number of files.
+fn segment_output_paths(output_path: &Path, stem: &str, ext: &str) -> Vec<String> {
+    let dir = output_path.parent().unwrap_or_else(|| Path::new("."));
+    let prefix = format!("{}_", stem);
+    let suffix = format!(".{}", ext);
+    let mut paths: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .filter(|p| {
+                    p.file_name()
+                        .and_then(|n| n.to_str())
+                        .map(|n| n.starts_with(&prefix) && n.ends_with(&suffix))
+                        .unwrap_or(false)
+                })
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    paths.sort();
+    paths
+}
+
+#[cfg(test)]
+mod segment_output_paths_tests {
+    use super::*;
+
+    fn unique_test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("audio_preprocessor_test_{}_{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn enumerates_and_sorts_matching_segment_files() {
+        let dir = unique_test_dir("segment_output_paths_match");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["clip_001.wav", "clip_000.wav", "clip_010.wav", "other.wav", "clip_000.flac"] {
+            std::fs::write(dir.join(name), b"").unwrap();
+        }
+
+        let output_path = dir.join("clip.wav");
+        let paths = segment_output_paths(&output_path, "clip", "wav");
+        let names: Vec<String> = paths
+            .iter()
+            .map(|p| Path::new(p).file_name().unwrap().to_str().unwrap().to_string())
+            .collect();
+
+        assert_eq!(names, vec!["clip_000.wav", "clip_001.wav", "clip_010.wav"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn returns_empty_when_no_segments_exist() {
+        let dir = unique_test_dir("segment_output_paths_empty");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let output_path = dir.join("clip.wav");
+        assert!(segment_output_paths(&output_path, "clip", "wav").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+/// Reads a media file's duration via `ffprobe`, defaulting to 0.0 if the
+/// probe fails or the output can't be parsed.
+fn probe_duration_sec(path: &str) -> f32 {
+    Command::new("ffprobe")
+        .args([
+            "-v",
+            "error",
+            "-show_entries",
+            "format=duration",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+            path,
+        ])
+        .output()
+        .ok()
+        .and_then(|o| String::from_utf8_lossy(&o.stdout).trim().parse().ok())
+        .unwrap_or(0.0)
+}
+
+/// If the trailing segment produced by the `-f segment` muxer is shorter
+/// than `min_duration_sec`, silence-pads it up to that length in place and
+/// returns `true`. Unlike the other clips in the run, a trailing segment's
+/// duration isn't known ahead of time (it's whatever is left over once the
+/// input runs out), so it can only be checked and fixed up after the fact.
+fn pad_short_segment_in_place(path: &str, min_duration_sec: f32) -> Result<bool, String> {
+    if probe_duration_sec(path) >= min_duration_sec {
+        return Ok(false);
+    }
+
+    let padded_path = format!("{}.padded", path);
+    // The `.padded` suffix hides the real extension from ffmpeg's format
+    // guessing, so name the muxer explicitly (wav, flac, opus and mp3 are
+    // all muxer names too).
+    let format = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("wav");
+    let status = Command::new("ffmpeg")
+        .args([
+            "-y",
+            "-v",
+            "error",
+            "-i",
+            path,
+            "-af",
+            &format!("apad=whole_dur={}", min_duration_sec),
+            "-f",
+            format,
+            &padded_path,
+        ])
+        .status()
+        .map_err(|e| format!("ffmpeg failed: {}", e))?;
+    if !status.success() {
+        return Err("ffmpeg returned non-zero while padding trailing segment".to_string());
+    }
+
+    std::fs::rename(&padded_path, path).map_err(|e| format!("failed to replace {}: {}", path, e))?;
+    Ok(true)
+}
+
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
     if args.len() < 3 {
@@ -129,6 +490,24 @@ fn main() {
               --min-duration <sec>   Minimum duration in seconds (default: 3.0)
               --max-duration <sec>   Maximum duration in seconds (default: 5.0)
               --threads <num>        Number of threads (default: auto)
+              --segment              Slice long files into consecutive max-duration clips
+                                      instead of truncating them
+              --filter-chain <str>   libavfilter chain applied before resampling
+                                      (e.g. \"loudnorm=I=-23:TP=-2:LRA=7\")
+              --output-codec <name>  Output encoder, e.g. pcm_f32le (default), flac,
+                                      libopus, libmp3lame
+              --normalize            Measure EBU R128 loudness per file and apply a
+                                      second-pass linear loudnorm to -23 LUFS; always
+                                      records the measurement in manifest.json
+              --io-timeout <ms>      I/O timeout for remote sources (default: 30000).
+                                      <input_dir> may also be a single http(s)://
+                                      or rtmp:// URL, or a text file listing one
+                                      URL per line
+              --decoder-option <k>=<v>
+                                      Extra demuxer/decoder option (probesize,
+                                      analyzeduration, threads, ...), passed as an
+                                      AVDictionary entry. May be repeated. Unrecognized
+                                      keys fail the conversion instead of being ignored
         ",
             args[0]
         );
@@ -143,6 +522,12 @@ fn main() {
         max_duration: 5.0,
         threads: 0,
         use_fork: false,
+        segment: false,
+        filter_chain: None,
+        output_codec: "pcm_f32le".to_string(),
+        normalize: false,
+        io_timeout_ms: 30_000,
+        decoder_options: Vec::new(),
     };
 
     let mut i = 3;
@@ -163,6 +548,25 @@ fn main() {
             i += 1;
         } else if args[i].eq("--use-fork") {
             config.use_fork = true;
+        } else if args[i].eq("--segment") {
+            config.segment = true;
+        } else if args[i].eq("--filter-chain") {
+            config.filter_chain = Some(args[i + 1].clone());
+            i += 1;
+        } else if args[i].eq("--output-codec") {
+            config.output_codec = args[i + 1].clone();
+            i += 1;
+        } else if args[i].eq("--normalize") {
+            config.normalize = true;
+        } else if args[i].eq("--io-timeout") {
+            config.io_timeout_ms = args[i + 1].parse().expect("--io-timeout must be an integer");
+            i += 1;
+        } else if args[i].eq("--decoder-option") {
+            let (key, value) = args[i + 1]
+                .split_once('=')
+                .expect("--decoder-option must be in the form key=value");
+            config.decoder_options.push((key.to_string(), value.to_string()));
+            i += 1;
         }
 
         i += 1;
@@ -185,13 +589,20 @@ fn main() {
         }
     );
 
+    processor::init_network();
+
     let processor_config = processor::ProcessorConfig {
         target_sample_rate: config.sample_rate,
         min_duration_sec: config.min_duration,
         max_duration_sec: config.max_duration,
+        segment: config.segment,
+        filter_chain: config.filter_chain.clone(),
+        output_codec: config.output_codec.clone(),
+        io_timeout_usec: Some(config.io_timeout_ms as i64 * 1000),
+        extra_options: config.decoder_options.clone(),
     };
 
-    let tasks = collect_tasks(&config.input_dir, &config.output_dir);
+    let tasks = collect_tasks(&config.input_dir, &config.output_dir, &config.output_codec);
     println!("Found {} audio files", tasks.len());
 
     if tasks.is_empty() {
@@ -218,23 +629,65 @@ fn main() {
 
     let processed = AtomicUsize::new(0);
     let failed = AtomicUsize::new(0);
+    let manifest = Mutex::new(Vec::with_capacity(tasks.len()));
 
     pool.install(|| {
         tasks.par_iter().for_each(|task| {
+            // Always measure loudness for the manifest; with --normalize the
+            // measurement also feeds a deterministic second-pass loudnorm.
+            let measurement = processor::measure_file(
+                task.input_path.to_str().unwrap(),
+                processor_config.io_timeout_usec,
+            );
+
+            let mut task_config = processor_config.clone();
+            if config.normalize {
+                if let Ok(m) = &measurement {
+                    let loudnorm = m.to_loudnorm_filter(-23.0, -2.0, 7.0);
+                    task_config.filter_chain = Some(match &task_config.filter_chain {
+                        Some(existing) => format!("{},{}", existing, loudnorm),
+                        None => loudnorm,
+                    });
+                }
+            }
+
             let result = if config.use_fork {
-                process_file_fork(task, &processor_config)
+                process_file_fork(task, &task_config)
             } else {
                 processor::process_file(
                     task.input_path.to_str().unwrap(),
                     task.output_path.to_str().unwrap(),
-                    &processor_config,
+                    &task_config,
                 )
             };
 
             match result {
-                Ok(()) => {
+                Ok(outcome) => {
                     processed.fetch_add(1, Ordering::Relaxed);
                     println!("Processed: {}", task.input_path.display());
+
+                    let m = measurement.unwrap_or(processor::LoudnessMeasurement {
+                        integrated_loudness: 0.0,
+                        true_peak: 0.0,
+                        loudness_range: 0.0,
+                        threshold: 0.0,
+                    });
+
+                    let mut manifest = manifest.lock().unwrap();
+                    for output in &outcome.outputs {
+                        manifest.push(ManifestEntry {
+                            input_path: task.input_path.to_string_lossy().into_owned(),
+                            output_path: output.path.clone(),
+                            input_sample_rate: outcome.input_sample_rate,
+                            output_sample_rate: config.sample_rate,
+                            measured_i: m.integrated_loudness,
+                            measured_tp: m.true_peak,
+                            measured_lra: m.loudness_range,
+                            duration_sec: output.duration_sec,
+                            truncated: output.truncated,
+                            padded: output.padded,
+                        });
+                    }
                 }
                 Err(e) => {
                     failed.fetch_add(1, Ordering::Relaxed);
@@ -249,4 +702,11 @@ fn main() {
         processed.load(Ordering::Relaxed),
         failed.load(Ordering::Relaxed)
     );
+
+    let manifest = manifest.into_inner().unwrap();
+    if let Err(e) = write_manifest(&config.output_dir, &manifest) {
+        eprintln!("Failed to write manifest.json: {}", e);
+    } else {
+        println!("Wrote manifest for {} files to {}/manifest.json", manifest.len(), config.output_dir);
+    }
 }
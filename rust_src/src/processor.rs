@@ -1,60 +1,749 @@
 use crate::ffmpeg::*;
 use std::ffi::CString;
+use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
 use std::ptr;
 
+#[derive(Clone)]
 pub struct ProcessorConfig {
     pub target_sample_rate: u32,
     pub min_duration_sec: f32,
     pub max_duration_sec: f32,
+    /// When true, `max_duration_sec` is treated as a segment length: long
+    /// inputs are sliced into consecutive `name_000.wav`, `name_001.wav`, ...
+    /// outputs instead of being truncated after the first segment.
+    pub segment: bool,
+    /// Optional libavfilter chain (e.g. `"loudnorm=I=-23:TP=-2:LRA=7"`)
+    /// applied to decoded frames before resampling.
+    pub filter_chain: Option<String>,
+    /// FFmpeg encoder name, e.g. "pcm_f32le" (default), "flac", "libopus".
+    pub output_codec: String,
+    /// I/O timeout (microseconds) passed as `rw_timeout` to
+    /// `avformat_open_input`, used for `http(s)://`/`rtmp://` sources that
+    /// can otherwise stall forever. `None` leaves FFmpeg's default.
+    pub io_timeout_usec: Option<i64>,
+    /// Extra demuxer/decoder options (probesize, analyzeduration, threads,
+    /// strict, ...) passed as an `AVDictionary` to `avformat_open_input` and
+    /// `avcodec_open2`. Keys FFmpeg doesn't recognize are reported as an
+    /// error rather than silently ignored.
+    pub extra_options: Vec<(String, String)>,
 }
 
-pub fn process_file(
-    input_path: &str,
-    output_path: &str,
-    config: &ProcessorConfig,
-) -> Result<(), String> {
-    let input_cstr = CString::new(input_path).map_err(|e| e.to_string())?;
-    let output_cstr = CString::new(output_path).map_err(|e| e.to_string())?;
-
+/// Initializes FFmpeg's network stack (sockets, TLS). Must be called once
+/// before opening any `http(s)://`/`rtmp://` input; safe to call even when
+/// every task is a local file.
+pub fn init_network() {
     unsafe {
-        let mut in_fmt_ctx: *mut AVFormatContext = ptr::null_mut();
-        let ret = avformat_open_input(
-            &mut in_fmt_ctx,
-            input_cstr.as_ptr(),
-            ptr::null(),
+        avformat_network_init();
+    }
+}
+
+/// Maps an encoder name to the muxer short name and file extension it's
+/// normally paired with.
+pub(crate) fn container_for_codec(codec_name: &str) -> (&'static str, &'static str) {
+    match codec_name {
+        "flac" => ("flac", "flac"),
+        "libopus" | "opus" => ("ogg", "opus"),
+        "libmp3lame" | "mp3" => ("mp3", "mp3"),
+        _ => ("wav", "wav"),
+    }
+}
+
+#[cfg(test)]
+mod container_for_codec_tests {
+    use super::*;
+
+    #[test]
+    fn maps_known_codecs_to_their_container() {
+        assert_eq!(container_for_codec("flac"), ("flac", "flac"));
+        assert_eq!(container_for_codec("libopus"), ("ogg", "opus"));
+        assert_eq!(container_for_codec("opus"), ("ogg", "opus"));
+        assert_eq!(container_for_codec("libmp3lame"), ("mp3", "mp3"));
+        assert_eq!(container_for_codec("mp3"), ("mp3", "mp3"));
+    }
+
+    #[test]
+    fn falls_back_to_wav_for_pcm_and_unknown_codecs() {
+        assert_eq!(container_for_codec("pcm_f32le"), ("wav", "wav"));
+        assert_eq!(container_for_codec("some_future_codec"), ("wav", "wav"));
+    }
+}
+
+/// EBU R128 loudness stats measured by [`measure_file`], in the same units
+/// `loudnorm`'s `measured_*` options expect (LUFS / dBTP / LU).
+#[derive(Debug, Clone, Copy)]
+pub struct LoudnessMeasurement {
+    pub integrated_loudness: f64,
+    pub true_peak: f64,
+    pub loudness_range: f64,
+    pub threshold: f64,
+}
+
+impl LoudnessMeasurement {
+    /// Builds a second-pass `loudnorm` filter in linear mode pinned to these
+    /// measured values, targeting `target_i`/`target_tp`/`target_lra`.
+    pub fn to_loudnorm_filter(&self, target_i: f64, target_tp: f64, target_lra: f64) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:linear=true",
+            target_i,
+            target_tp,
+            target_lra,
+            self.integrated_loudness,
+            self.true_peak,
+            self.loudness_range,
+            self.threshold
+        )
+    }
+}
+
+/// One file actually written. With `segment` set, a single task produces
+/// several of these (one per `name_000.wav`, `name_001.wav`, ... clip); each
+/// carries its own duration/truncated/padded since only the trailing clip
+/// can be short or silence-padded -- the others are always exactly
+/// `max_duration_sec` long.
+#[derive(Debug, Clone)]
+pub struct OutputFile {
+    pub path: String,
+    pub duration_sec: f32,
+    pub truncated: bool,
+    pub padded: bool,
+}
+
+/// Outcome of converting one file, used to populate a dataset manifest.
+#[derive(Debug, Clone)]
+pub struct ProcessOutcome {
+    pub input_sample_rate: u32,
+    pub outputs: Vec<OutputFile>,
+}
+
+// --- RAII wrappers over the raw FFmpeg FFI -------------------------------
+//
+// Each type owns a raw pointer and frees it in `Drop`, so every early return
+// in `process_file_inner` (via `?`) cleans up automatically instead of
+// repeating a manual teardown chain.
+
+// --- Custom AVIO streaming I/O ------------------------------------------
+//
+// Lets callers demux/decode from an in-memory buffer or a streaming channel
+// instead of a path, by wrapping `avio_alloc_context` with Rust-side
+// read/seek callbacks.
+
+/// A non-file audio source fed into FFmpeg's demuxer through a custom
+/// `AVIOContext`. Implement this to decode from an in-memory buffer or a
+/// streaming channel rather than a path passed to `avformat_open_input`.
+pub trait StreamSource: Send {
+    /// Fills up to `buf.len()` bytes, returning the number of bytes
+    /// actually written (which may be less than `buf.len()` on a partial
+    /// read), or `0` at end of stream.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Seeks to `offset`, interpreted per `whence` (the C `SEEK_SET` /
+    /// `SEEK_CUR` / `SEEK_END` constants), returning the new absolute
+    /// position. Returns `None` when the source isn't seekable, so formats
+    /// that need to probe (MP3, ADTS) still work against unseekable input.
+    fn seek(&mut self, _offset: i64, _whence: i32) -> Option<i64> {
+        None
+    }
+}
+
+/// AVSEEK_SIZE isn't part of bindgen's `AVFMT_.*`/`AVIO_FLAG_.*` allowlists
+/// since it's only ever used as a `whence` sentinel to `seek_cb`, not a
+/// real flag or AVFormat constant.
+const AVSEEK_SIZE: i32 = 0x10000;
+
+/// 4 KiB: comfortably within the 4-32 KiB range FFmpeg's own demuxers use
+/// for their internal AVIO buffers.
+const AVIO_BUFFER_SIZE: usize = 4096;
+
+unsafe extern "C" fn avio_read_cb(opaque: *mut c_void, buf: *mut u8, buf_size: i32) -> i32 {
+    let source = &mut *(opaque as *mut Box<dyn StreamSource>);
+    let out = std::slice::from_raw_parts_mut(buf, buf_size.max(0) as usize);
+    let n = source.read(out);
+    if n == 0 {
+        AVERROR_EOF
+    } else {
+        n as i32
+    }
+}
+
+unsafe extern "C" fn avio_seek_cb(opaque: *mut c_void, offset: i64, whence: i32) -> i64 {
+    let source = &mut *(opaque as *mut Box<dyn StreamSource>);
+    if whence & AVSEEK_SIZE != 0 {
+        return -1;
+    }
+    match source.seek(offset, whence) {
+        Some(pos) => pos,
+        None => -1,
+    }
+}
+
+/// Owns the `AVIOContext` plus the boxed [`StreamSource`] trait object its
+/// opaque pointer refers to, freeing both together.
+struct AvioReader {
+    ctx: *mut AVIOContext,
+    source: *mut Box<dyn StreamSource>,
+}
+
+impl AvioReader {
+    fn new(source: Box<dyn StreamSource>) -> Result<Self, String> {
+        unsafe {
+            let buffer = av_malloc(AVIO_BUFFER_SIZE) as *mut u8;
+            if buffer.is_null() {
+                return Err("Failed to alloc AVIO buffer".to_string());
+            }
+
+            let source_ptr = Box::into_raw(Box::new(source));
+
+            let ctx = avio_alloc_context(
+                buffer,
+                AVIO_BUFFER_SIZE as i32,
+                0, // write_flag: read-only
+                source_ptr as *mut c_void,
+                Some(avio_read_cb),
+                None,
+                Some(avio_seek_cb),
+            );
+            if ctx.is_null() {
+                av_free(buffer as *mut c_void);
+                drop(Box::from_raw(source_ptr));
+                return Err("Failed to alloc AVIOContext".to_string());
+            }
+
+            Ok(AvioReader { ctx, source: source_ptr })
+        }
+    }
+}
+
+impl Drop for AvioReader {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.ctx.is_null() {
+                av_free((*self.ctx).buffer as *mut c_void);
+                avio_context_free(&mut self.ctx);
+            }
+            drop(Box::from_raw(self.source));
+        }
+    }
+}
+
+/// String key/value options threaded into `avformat_open_input` /
+/// `avcodec_open2` as an `AVDictionary`. FFmpeg consumes recognized entries
+/// and leaves the rest in the dictionary, so [`DictOptions::into_unconsumed`]
+/// surfaces whatever's left after the call instead of silently dropping it.
+struct DictOptions(*mut AVDictionary);
+
+impl DictOptions {
+    fn from_pairs(pairs: &[(String, String)]) -> Result<Self, String> {
+        let mut ptr: *mut AVDictionary = ptr::null_mut();
+        unsafe {
+            for (key, value) in pairs {
+                let key_cstr = CString::new(key.as_str()).map_err(|e| e.to_string())?;
+                let value_cstr = CString::new(value.as_str()).map_err(|e| e.to_string())?;
+                av_dict_set(&mut ptr, key_cstr.as_ptr(), value_cstr.as_ptr(), 0);
+            }
+        }
+        Ok(DictOptions(ptr))
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut *mut AVDictionary {
+        &mut self.0
+    }
+
+    /// Drains the dictionary's remaining entries (whatever FFmpeg didn't
+    /// recognize/consume) as key/value pairs.
+    unsafe fn into_unconsumed(self) -> Vec<(String, String)> {
+        let mut leftover = Vec::new();
+        let empty_key = CString::new("").unwrap();
+        let mut entry: *mut AVDictionaryEntry = ptr::null_mut();
+        loop {
+            entry = av_dict_get(self.0, empty_key.as_ptr(), entry, AV_DICT_IGNORE_SUFFIX as i32);
+            if entry.is_null() {
+                break;
+            }
+            let key = std::ffi::CStr::from_ptr((*entry).key).to_string_lossy().into_owned();
+            let value = std::ffi::CStr::from_ptr((*entry).value).to_string_lossy().into_owned();
+            leftover.push((key, value));
+        }
+        leftover
+    }
+}
+
+impl Drop for DictOptions {
+    fn drop(&mut self) {
+        unsafe {
+            av_dict_free(&mut self.0);
+        }
+    }
+}
+
+fn err_on_unconsumed(leftover: Vec<(String, String)>, what: &str) -> Result<(), String> {
+    if leftover.is_empty() {
+        return Ok(());
+    }
+    let keys: Vec<&str> = leftover.iter().map(|(k, _)| k.as_str()).collect();
+    Err(format!("Unrecognized {} option(s): {}", what, keys.join(", ")))
+}
+
+struct InputFormatContext(*mut AVFormatContext, Option<AvioReader>);
+
+impl InputFormatContext {
+    /// Returns the opened context plus whatever options the format layer
+    /// didn't recognize. A decoder-only option (e.g. `threads`) is expected
+    /// to show up here unconsumed -- the caller is responsible for handing
+    /// these on to `CodecContext::open` rather than treating them as errors
+    /// at this point; only a key still unconsumed after that call is
+    /// genuinely invalid.
+    unsafe fn open(
+        path: &CString,
+        io_timeout_usec: Option<i64>,
+        extra_options: &[(String, String)],
+    ) -> Result<(Self, Vec<(String, String)>), String> {
+        let mut pairs = extra_options.to_vec();
+        if let Some(timeout) = io_timeout_usec {
+            pairs.push(("rw_timeout".to_string(), timeout.to_string()));
+        }
+        let mut options = DictOptions::from_pairs(&pairs)?;
+
+        let mut ctx: *mut AVFormatContext = ptr::null_mut();
+        let ret = avformat_open_input(&mut ctx, path.as_ptr(), ptr::null(), options.as_mut_ptr());
+        if ret < 0 || ctx.is_null() {
+            return Err("Failed to open input".to_string());
+        }
+
+        let leftover = options.into_unconsumed();
+        Ok((InputFormatContext(ctx, None), leftover))
+    }
+
+    /// Opens a demuxer against a [`StreamSource`] instead of a file path, by
+    /// pre-allocating the `AVFormatContext`, pointing its `pb` at a custom
+    /// `AVIOContext`, and setting `AVFMT_FLAG_CUSTOM_IO` so
+    /// `avformat_close_input` leaves that `AVIOContext` alone (we free it
+    /// ourselves once the reader field drops). See `open` for how leftover
+    /// options are handled.
+    unsafe fn open_with_reader(
+        reader: AvioReader,
+        io_timeout_usec: Option<i64>,
+        extra_options: &[(String, String)],
+    ) -> Result<(Self, Vec<(String, String)>), String> {
+        let mut ctx = avformat_alloc_context();
+        if ctx.is_null() {
+            return Err("Failed to alloc format context".to_string());
+        }
+        (*ctx).pb = reader.ctx;
+        (*ctx).flags |= AVFMT_FLAG_CUSTOM_IO as i32;
+
+        let mut pairs = extra_options.to_vec();
+        if let Some(timeout) = io_timeout_usec {
+            pairs.push(("rw_timeout".to_string(), timeout.to_string()));
+        }
+        let mut options = DictOptions::from_pairs(&pairs)?;
+
+        let empty_path = CString::new("").unwrap();
+        let ret = avformat_open_input(&mut ctx, empty_path.as_ptr(), ptr::null(), options.as_mut_ptr());
+        if ret < 0 || ctx.is_null() {
+            return Err("Failed to open custom I/O input".to_string());
+        }
+
+        let leftover = options.into_unconsumed();
+        Ok((InputFormatContext(ctx, Some(reader)), leftover))
+    }
+
+    fn as_ptr(&self) -> *mut AVFormatContext {
+        self.0
+    }
+}
+
+impl Drop for InputFormatContext {
+    fn drop(&mut self) {
+        unsafe {
+            avformat_close_input(&mut self.0);
+        }
+    }
+}
+
+struct OutputFormatContext {
+    ptr: *mut AVFormatContext,
+    header_written: bool,
+    io_opened: bool,
+}
+
+impl OutputFormatContext {
+    unsafe fn create(container: &CString, path: &CString) -> Result<Self, String> {
+        let mut ptr: *mut AVFormatContext = ptr::null_mut();
+        let ret = avformat_alloc_output_context2(&mut ptr, ptr::null(), container.as_ptr(), path.as_ptr());
+        if ret < 0 || ptr.is_null() {
+            return Err("Failed to alloc output".to_string());
+        }
+        Ok(OutputFormatContext {
+            ptr,
+            header_written: false,
+            io_opened: false,
+        })
+    }
+
+    fn as_ptr(&self) -> *mut AVFormatContext {
+        self.ptr
+    }
+
+    unsafe fn new_stream(&mut self, encoder: *const AVCodec) -> Result<*mut AVStream, String> {
+        let stream = avformat_new_stream(self.ptr, encoder);
+        if stream.is_null() {
+            return Err("Failed to create stream".to_string());
+        }
+        Ok(stream)
+    }
+
+    unsafe fn open_io(&mut self, path: &CString) -> Result<(), String> {
+        if ((*(*self.ptr).oformat).flags & AVFMT_NOFILE as i32) == 0 {
+            let ret = avio_open(&mut (*self.ptr).pb, path.as_ptr(), AVIO_FLAG_WRITE as i32);
+            if ret < 0 {
+                return Err("Failed to open output file".to_string());
+            }
+            self.io_opened = true;
+        }
+        Ok(())
+    }
+
+    unsafe fn write_header(&mut self) -> Result<(), String> {
+        let ret = avformat_write_header(self.ptr, ptr::null_mut());
+        if ret < 0 {
+            return Err("Failed to write header".to_string());
+        }
+        self.header_written = true;
+        Ok(())
+    }
+
+    unsafe fn write_frame(&mut self, pkt: *mut AVPacket) {
+        av_interleaved_write_frame(self.ptr, pkt);
+    }
+}
+
+impl Drop for OutputFormatContext {
+    fn drop(&mut self) {
+        unsafe {
+            if self.ptr.is_null() {
+                return;
+            }
+            if self.header_written {
+                av_write_trailer(self.ptr);
+            }
+            if self.io_opened {
+                avio_closep(&mut (*self.ptr).pb);
+            }
+            avformat_free_context(self.ptr);
+        }
+    }
+}
+
+struct CodecContext(*mut AVCodecContext);
+
+impl CodecContext {
+    unsafe fn new(codec: *const AVCodec) -> Result<Self, String> {
+        let ctx = avcodec_alloc_context3(codec);
+        if ctx.is_null() {
+            return Err("Failed to alloc codec context".to_string());
+        }
+        Ok(CodecContext(ctx))
+    }
+
+    fn as_ptr(&self) -> *mut AVCodecContext {
+        self.0
+    }
+
+    unsafe fn open(&mut self, codec: *const AVCodec, extra_options: &[(String, String)]) -> Result<(), String> {
+        let mut options = DictOptions::from_pairs(extra_options)?;
+        let ret = avcodec_open2(self.0, codec, options.as_mut_ptr());
+        if ret < 0 {
+            return Err("Failed to open codec".to_string());
+        }
+        err_on_unconsumed(options.into_unconsumed(), "decoder")
+    }
+}
+
+impl Drop for CodecContext {
+    fn drop(&mut self) {
+        unsafe {
+            avcodec_free_context(&mut self.0);
+        }
+    }
+}
+
+struct Resampler(*mut SwrContext);
+
+impl Resampler {
+    unsafe fn new(
+        dst_ch_layout: &mut AVChannelLayout,
+        dst_sample_fmt: AVSampleFormat,
+        dst_sample_rate: i32,
+        in_ch_layout: &AVChannelLayout,
+        in_sample_fmt: AVSampleFormat,
+        in_sample_rate: i32,
+    ) -> Result<Self, String> {
+        let mut ctx: *mut SwrContext = ptr::null_mut();
+        let ret = swr_alloc_set_opts2(
+            &mut ctx,
+            dst_ch_layout as *mut _,
+            dst_sample_fmt,
+            dst_sample_rate,
+            in_ch_layout as *const _ as *mut _,
+            in_sample_fmt,
+            in_sample_rate,
+            0,
             ptr::null_mut(),
         );
-        if ret < 0 || in_fmt_ctx.is_null() {
-            return Err("Failed to open input".to_string());
+        if ret < 0 || ctx.is_null() {
+            return Err("Failed to alloc resampler".to_string());
         }
 
-        let result = process_file_inner(in_fmt_ctx, &output_cstr, config);
+        let filter_size_cstr = CString::new("filter_size").unwrap();
+        let cutoff_cstr = CString::new("cutoff").unwrap();
+        av_opt_set_int(ctx as *mut _, filter_size_cstr.as_ptr(), 64, 0);
+        av_opt_set_double(ctx as *mut _, cutoff_cstr.as_ptr(), 0.97, 0);
+
+        let mut resampler = Resampler(ctx);
+        if swr_init(resampler.0) < 0 {
+            return Err("Failed to init resampler".to_string());
+        }
+        // swr_init initializes the context in place and keeps the same
+        // handle, so `ctx` is still the pointer to own.
+        resampler.0 = ctx;
+        Ok(resampler)
+    }
 
-        avformat_close_input(&mut in_fmt_ctx);
-        result
+    fn as_ptr(&self) -> *mut SwrContext {
+        self.0
     }
 }
 
-unsafe fn process_file_inner(
-    in_fmt_ctx: *mut AVFormatContext,
-    output_cstr: &CString,
-    config: &ProcessorConfig,
-) -> Result<(), String> {
-    let wav_cstr = CString::new("wav").unwrap();
-    let filter_size_cstr = CString::new("filter_size").unwrap();
-    let cutoff_cstr = CString::new("cutoff").unwrap();
+impl Drop for Resampler {
+    fn drop(&mut self) {
+        unsafe {
+            swr_free(&mut self.0);
+        }
+    }
+}
+
+struct Frame(*mut AVFrame);
+
+impl Frame {
+    fn new() -> Result<Self, String> {
+        let ptr = unsafe { av_frame_alloc() };
+        if ptr.is_null() {
+            return Err("Failed to alloc frame".to_string());
+        }
+        Ok(Frame(ptr))
+    }
+
+    fn as_ptr(&self) -> *mut AVFrame {
+        self.0
+    }
+
+    fn unref(&self) {
+        unsafe {
+            av_frame_unref(self.0);
+        }
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        unsafe {
+            av_frame_free(&mut self.0);
+        }
+    }
+}
+
+struct Packet(*mut AVPacket);
+
+impl Packet {
+    fn new() -> Result<Self, String> {
+        let ptr = unsafe { av_packet_alloc() };
+        if ptr.is_null() {
+            return Err("Failed to alloc packet".to_string());
+        }
+        Ok(Packet(ptr))
+    }
+
+    fn as_ptr(&self) -> *mut AVPacket {
+        self.0
+    }
 
-    let mut ret: i32;
+    fn unref(&self) {
+        unsafe {
+            av_packet_unref(self.0);
+        }
+    }
+}
 
-    ret = avformat_find_stream_info(in_fmt_ctx, ptr::null_mut());
+impl Drop for Packet {
+    fn drop(&mut self) {
+        unsafe {
+            av_packet_free(&mut self.0);
+        }
+    }
+}
+
+struct FilterGraph {
+    graph: *mut AVFilterGraph,
+    src_ctx: *mut AVFilterContext,
+    sink_ctx: *mut AVFilterContext,
+}
+
+impl Drop for FilterGraph {
+    fn drop(&mut self) {
+        unsafe {
+            avfilter_graph_free(&mut self.graph);
+        }
+    }
+}
+
+unsafe fn build_filter_graph(
+    filter_chain: &str,
+    in_sample_rate: i32,
+    in_sample_fmt: AVSampleFormat,
+    in_ch_layout: &AVChannelLayout,
+) -> Result<FilterGraph, String> {
+    let graph = avfilter_graph_alloc();
+    if graph.is_null() {
+        return Err("Failed to alloc filter graph".to_string());
+    }
+
+    let mut ch_layout_buf = [0i8; 64];
+    av_channel_layout_describe(
+        in_ch_layout as *const _ as *mut _,
+        ch_layout_buf.as_mut_ptr(),
+        ch_layout_buf.len(),
+    );
+    let ch_layout_str = std::ffi::CStr::from_ptr(ch_layout_buf.as_ptr())
+        .to_string_lossy()
+        .into_owned();
+
+    let fmt_name_ptr = av_get_sample_fmt_name(in_sample_fmt);
+    let fmt_name = if fmt_name_ptr.is_null() {
+        "flt".to_string()
+    } else {
+        std::ffi::CStr::from_ptr(fmt_name_ptr)
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    let src_args = format!(
+        "time_base=1/{}:sample_rate={}:sample_fmt={}:channel_layout={}",
+        in_sample_rate, in_sample_rate, fmt_name, ch_layout_str
+    );
+
+    let abuffer_name = CString::new("abuffer").unwrap();
+    let abuffersink_name = CString::new("abuffersink").unwrap();
+    let src_inst = CString::new("in").unwrap();
+    let sink_inst = CString::new("out").unwrap();
+    let src_args_cstr = CString::new(src_args).unwrap();
+
+    let abuffer = avfilter_get_by_name(abuffer_name.as_ptr());
+    let abuffersink = avfilter_get_by_name(abuffersink_name.as_ptr());
+    if abuffer.is_null() || abuffersink.is_null() {
+        avfilter_graph_free(&mut (graph as *mut _));
+        return Err("abuffer/abuffersink filters not available".to_string());
+    }
+
+    let mut src_ctx: *mut AVFilterContext = ptr::null_mut();
+    let mut ret = avfilter_graph_create_filter(
+        &mut src_ctx,
+        abuffer,
+        src_inst.as_ptr(),
+        src_args_cstr.as_ptr(),
+        ptr::null_mut(),
+        graph,
+    );
+    if ret < 0 || src_ctx.is_null() {
+        avfilter_graph_free(&mut (graph as *mut _));
+        return Err("Failed to create abuffer source".to_string());
+    }
+
+    let mut sink_ctx: *mut AVFilterContext = ptr::null_mut();
+    ret = avfilter_graph_create_filter(
+        &mut sink_ctx,
+        abuffersink,
+        sink_inst.as_ptr(),
+        ptr::null(),
+        ptr::null_mut(),
+        graph,
+    );
+    if ret < 0 || sink_ctx.is_null() {
+        avfilter_graph_free(&mut (graph as *mut _));
+        return Err("Failed to create abuffersink".to_string());
+    }
+
+    let mut inputs = avfilter_inout_alloc();
+    let mut outputs = avfilter_inout_alloc();
+    if inputs.is_null() || outputs.is_null() {
+        avfilter_inout_free(&mut inputs);
+        avfilter_inout_free(&mut outputs);
+        avfilter_graph_free(&mut (graph as *mut _));
+        return Err("Failed to alloc filter in/out".to_string());
+    }
+
+    (*outputs).name = av_strdup(src_inst.as_ptr());
+    (*outputs).filter_ctx = src_ctx;
+    (*outputs).pad_idx = 0;
+    (*outputs).next = ptr::null_mut();
+
+    (*inputs).name = av_strdup(sink_inst.as_ptr());
+    (*inputs).filter_ctx = sink_ctx;
+    (*inputs).pad_idx = 0;
+    (*inputs).next = ptr::null_mut();
+
+    let chain_cstr = match CString::new(filter_chain) {
+        Ok(c) => c,
+        Err(e) => {
+            avfilter_inout_free(&mut inputs);
+            avfilter_inout_free(&mut outputs);
+            avfilter_graph_free(&mut (graph as *mut _));
+            return Err(e.to_string());
+        }
+    };
+
+    ret = avfilter_graph_parse_ptr(
+        graph,
+        chain_cstr.as_ptr(),
+        &mut inputs,
+        &mut outputs,
+        ptr::null_mut(),
+    );
+    avfilter_inout_free(&mut inputs);
+    avfilter_inout_free(&mut outputs);
+    if ret < 0 {
+        avfilter_graph_free(&mut (graph as *mut _));
+        return Err("Failed to parse filter chain".to_string());
+    }
+
+    ret = avfilter_graph_config(graph, ptr::null_mut());
+    if ret < 0 {
+        avfilter_graph_free(&mut (graph as *mut _));
+        return Err("Failed to configure filter graph".to_string());
+    }
+
+    Ok(FilterGraph {
+        graph,
+        src_ctx,
+        sink_ctx,
+    })
+}
+
+/// Finds the best audio stream and opens its decoder, shared by
+/// `process_file_inner` and `measure_file_inner`.
+unsafe fn open_decoder(
+    in_fmt_ctx: &InputFormatContext,
+    extra_options: &[(String, String)],
+) -> Result<(CodecContext, i32, AVChannelLayout, AVSampleFormat, i32), String> {
+    let ret = avformat_find_stream_info(in_fmt_ctx.as_ptr(), ptr::null_mut());
     if ret < 0 {
         return Err("Failed to find stream info".to_string());
     }
 
     let mut decoder: *const AVCodec = ptr::null();
     let stream_index = av_find_best_stream(
-        in_fmt_ctx,
-        AVMediaType_AVMEDIA_TYPE_AUDIO,
+        in_fmt_ctx.as_ptr(),
+        AVMEDIA_TYPE_AUDIO,
         -1,
         -1,
         &mut decoder,
@@ -63,412 +752,742 @@ unsafe fn process_file_inner(
     if stream_index < 0 {
         return Err("No audio stream".to_string());
     }
-
-    let in_stream = *(*in_fmt_ctx).streams.add(stream_index as usize);
-    let codecpar = (*in_stream).codecpar;
-
     if decoder.is_null() {
         return Err("No decoder".to_string());
     }
 
-    let dec_ctx = avcodec_alloc_context3(decoder);
-    if dec_ctx.is_null() {
-        return Err("Failed to alloc decoder context".to_string());
-    }
+    let in_stream = *(*in_fmt_ctx.as_ptr()).streams.add(stream_index as usize);
+    let codecpar = (*in_stream).codecpar;
 
-    ret = avcodec_parameters_to_context(dec_ctx, codecpar);
-    if ret < 0 {
-        avcodec_free_context(&mut (dec_ctx as *mut _));
+    let mut dec_ctx = CodecContext::new(decoder)?;
+    if avcodec_parameters_to_context(dec_ctx.as_ptr(), codecpar) < 0 {
         return Err("Failed to copy params".to_string());
     }
+    dec_ctx.open(decoder, extra_options)?;
 
-    ret = avcodec_open2(dec_ctx, decoder, ptr::null_mut());
-    if ret < 0 {
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to open decoder".to_string());
+    let in_sample_rate = (*dec_ctx.as_ptr()).sample_rate;
+    let in_ch_layout = (*dec_ctx.as_ptr()).ch_layout;
+    let in_sample_fmt = (*dec_ctx.as_ptr()).sample_fmt;
+
+    Ok((dec_ctx, stream_index, in_ch_layout, in_sample_fmt, in_sample_rate))
+}
+
+unsafe fn read_r128_metadata(frame: *mut AVFrame, key: &str) -> Option<f64> {
+    let key_cstr = CString::new(key).ok()?;
+    let entry = av_dict_get((*frame).metadata, key_cstr.as_ptr(), ptr::null(), 0);
+    if entry.is_null() {
+        return None;
     }
+    std::ffi::CStr::from_ptr((*entry).value).to_str().ok()?.parse().ok()
+}
 
-    let in_sample_rate = (*dec_ctx).sample_rate;
-    let in_ch_layout = (*dec_ctx).ch_layout;
-    let channels = if in_ch_layout.nb_channels == 0 {
-        2
-    } else {
-        in_ch_layout.nb_channels
+/// Runs decoded frames through an `ebur128` filter to collect integrated
+/// loudness, true peak, and loudness range, without writing any output.
+/// Used as the first pass of `--normalize`'s two-pass loudnorm, and to
+/// record provenance in the dataset manifest even when normalization is
+/// off.
+pub fn measure_file(input_path: &str, io_timeout_usec: Option<i64>) -> Result<LoudnessMeasurement, String> {
+    let input_cstr = CString::new(input_path).map_err(|e| e.to_string())?;
+    unsafe {
+        let (in_fmt_ctx, leftover) = InputFormatContext::open(&input_cstr, io_timeout_usec, &[])?;
+        err_on_unconsumed(leftover, "format")?;
+        measure_file_inner(&in_fmt_ctx)
+    }
+}
+
+unsafe fn measure_file_inner(in_fmt_ctx: &InputFormatContext) -> Result<LoudnessMeasurement, String> {
+    let (mut dec_ctx, stream_index, in_ch_layout, in_sample_fmt, in_sample_rate) =
+        open_decoder(in_fmt_ctx, &[])?;
+
+    let fg = build_filter_graph(
+        "ebur128=peak=true:metadata=1",
+        in_sample_rate,
+        in_sample_fmt,
+        &in_ch_layout,
+    )?;
+
+    let dec_frame = Frame::new()?;
+    let filt_frame = Frame::new()?;
+    let pkt = Packet::new()?;
+
+    let mut measurement = LoudnessMeasurement {
+        integrated_loudness: -70.0,
+        true_peak: -99.0,
+        loudness_range: 0.0,
+        threshold: -70.0,
     };
-    let in_sample_fmt = (*dec_ctx).sample_fmt;
 
-    // Output setup
-    let mut out_fmt_ctx: *mut AVFormatContext = ptr::null_mut();
-    ret = avformat_alloc_output_context2(
-        &mut out_fmt_ctx,
-        ptr::null(),
-        wav_cstr.as_ptr(),
-        output_cstr.as_ptr(),
-    );
-    if ret < 0 || out_fmt_ctx.is_null() {
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to alloc output".to_string());
+    macro_rules! drain_sink {
+        () => {
+            while av_buffersink_get_frame(fg.sink_ctx, filt_frame.as_ptr()) >= 0 {
+                if let Some(v) = read_r128_metadata(filt_frame.as_ptr(), "lavfi.r128.I") {
+                    measurement.integrated_loudness = v;
+                }
+                if let Some(v) = read_r128_metadata(filt_frame.as_ptr(), "lavfi.r128.true_peak") {
+                    measurement.true_peak = v;
+                }
+                if let Some(v) = read_r128_metadata(filt_frame.as_ptr(), "lavfi.r128.LRA") {
+                    measurement.loudness_range = v;
+                }
+                if let Some(v) = read_r128_metadata(filt_frame.as_ptr(), "lavfi.r128.LRA.low") {
+                    measurement.threshold = v;
+                }
+                filt_frame.unref();
+            }
+        };
     }
 
-    let encoder = avcodec_find_encoder(AVCodecID_AV_CODEC_ID_PCM_F32LE);
-    if encoder.is_null() {
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("No encoder".to_string());
-    }
+    while av_read_frame(in_fmt_ctx.as_ptr(), pkt.as_ptr()) >= 0 {
+        if (*pkt.as_ptr()).stream_index != stream_index {
+            pkt.unref();
+            continue;
+        }
 
-    let out_stream = avformat_new_stream(out_fmt_ctx, encoder);
-    if out_stream.is_null() {
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to create stream".to_string());
+        let send_ret = avcodec_send_packet(dec_ctx.as_ptr(), pkt.as_ptr());
+        pkt.unref();
+        if send_ret < 0 {
+            continue;
+        }
+
+        while avcodec_receive_frame(dec_ctx.as_ptr(), dec_frame.as_ptr()) >= 0 {
+            if av_buffersrc_add_frame(fg.src_ctx, dec_frame.as_ptr()) >= 0 {
+                drain_sink!();
+            }
+            dec_frame.unref();
+        }
     }
 
-    let enc_ctx = avcodec_alloc_context3(encoder);
-    if enc_ctx.is_null() {
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to alloc encoder context".to_string());
+    av_buffersrc_add_frame(fg.src_ctx, ptr::null_mut());
+    drain_sink!();
+
+    Ok(measurement)
+}
+
+// --- Output segment -------------------------------------------------------
+
+struct OutputSegment {
+    fmt_ctx: OutputFormatContext,
+    stream: *mut AVStream,
+    enc_ctx: CodecContext,
+    pts: i64,
+    samples_written: usize,
+    sample_fmt: AVSampleFormat,
+    planar: bool,
+    bytes_per_sample: usize,
+    /// Encoder-reported frame size, or 0 when the encoder accepts any
+    /// `nb_samples` (e.g. PCM).
+    frame_size: usize,
+    /// Per-plane leftover bytes not yet large enough to form a full frame.
+    /// Has a single entry for packed formats.
+    pending: Vec<Vec<u8>>,
+}
+
+/// Picks the encoder's first supported sample format, falling back to FLT
+/// when the encoder declares no preference (common for PCM codecs).
+unsafe fn pick_sample_fmt(encoder: *const AVCodec) -> AVSampleFormat {
+    let fmts = (*encoder).sample_fmts;
+    if fmts.is_null() || *fmts == AV_SAMPLE_FMT_NONE {
+        return AV_SAMPLE_FMT_FLT;
     }
+    *fmts
+}
 
-    (*enc_ctx).sample_fmt = AVSampleFormat_AV_SAMPLE_FMT_FLT;
-    (*enc_ctx).sample_rate = config.target_sample_rate as i32;
-    av_channel_layout_default(&mut (*enc_ctx).ch_layout, channels);
-    (*enc_ctx).bit_rate = (config.target_sample_rate * channels as u32 * 32) as i64;
-    (*enc_ctx).time_base = AVRational {
-        num: 1,
-        den: config.target_sample_rate as i32,
-    };
+impl OutputSegment {
+    unsafe fn open(
+        output_cstr: &CString,
+        container_cstr: &CString,
+        encoder: *const AVCodec,
+        channels: i32,
+        config: &ProcessorConfig,
+    ) -> Result<Self, String> {
+        let mut fmt_ctx = OutputFormatContext::create(container_cstr, output_cstr)?;
+        let stream = fmt_ctx.new_stream(encoder)?;
+
+        let mut enc_ctx = CodecContext::new(encoder)?;
+        let sample_fmt = pick_sample_fmt(encoder);
+        let is_pcm = config.output_codec.starts_with("pcm_");
+
+        (*enc_ctx.as_ptr()).sample_fmt = sample_fmt;
+        (*enc_ctx.as_ptr()).sample_rate = config.target_sample_rate as i32;
+        av_channel_layout_default(&mut (*enc_ctx.as_ptr()).ch_layout, channels);
+        (*enc_ctx.as_ptr()).bit_rate = if is_pcm {
+            (config.target_sample_rate
+                * channels as u32
+                * av_get_bytes_per_sample(sample_fmt) as u32
+                * 8) as i64
+        } else {
+            128_000
+        };
+        (*enc_ctx.as_ptr()).time_base = AVRational {
+            num: 1,
+            den: config.target_sample_rate as i32,
+        };
+
+        if ((*fmt_ctx.as_ptr()).oformat.as_ref().unwrap().flags & AVFMT_GLOBALHEADER as i32) != 0 {
+            (*enc_ctx.as_ptr()).flags |= AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+        }
 
-    if ((*(*out_fmt_ctx).oformat).flags & AVFMT_GLOBALHEADER as i32) != 0 {
-        (*enc_ctx).flags |= AV_CODEC_FLAG_GLOBAL_HEADER as i32;
+        enc_ctx.open(encoder, &[])?;
+
+        let ret = avcodec_parameters_from_context((*stream).codecpar, enc_ctx.as_ptr());
+        if ret < 0 {
+            return Err("Failed to copy encoder params".to_string());
+        }
+
+        (*stream).time_base = AVRational {
+            num: 1,
+            den: config.target_sample_rate as i32,
+        };
+
+        fmt_ctx.open_io(output_cstr)?;
+        fmt_ctx.write_header()?;
+
+        let planar = av_sample_fmt_is_planar((*enc_ctx.as_ptr()).sample_fmt) != 0;
+        let plane_count = if planar { channels as usize } else { 1 };
+
+        Ok(OutputSegment {
+            sample_fmt: (*enc_ctx.as_ptr()).sample_fmt,
+            bytes_per_sample: av_get_bytes_per_sample((*enc_ctx.as_ptr()).sample_fmt) as usize,
+            frame_size: (*enc_ctx.as_ptr()).frame_size.max(0) as usize,
+            fmt_ctx,
+            stream,
+            enc_ctx,
+            pts: 0,
+            samples_written: 0,
+            planar,
+            pending: vec![Vec::new(); plane_count],
+        })
     }
 
-    ret = avcodec_open2(enc_ctx, encoder, ptr::null_mut());
-    if ret < 0 {
-        avcodec_free_context(&mut (enc_ctx as *mut _));
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to open encoder".to_string());
+    fn samples_per_plane(&self, channels: i32, bytes: usize) -> usize {
+        if self.planar {
+            bytes / self.bytes_per_sample
+        } else {
+            bytes / (self.bytes_per_sample * channels as usize)
+        }
     }
 
-    ret = avcodec_parameters_from_context((*out_stream).codecpar, enc_ctx);
-    if ret < 0 {
-        avcodec_free_context(&mut (enc_ctx as *mut _));
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to copy encoder params".to_string());
+    unsafe fn send_frame(
+        &mut self,
+        take: usize,
+        channels: i32,
+        enc_frame: &Frame,
+        out_pkt: &Packet,
+        config: &ProcessorConfig,
+    ) -> Result<(), String> {
+        enc_frame.unref();
+        let frame = enc_frame.as_ptr();
+        (*frame).format = self.sample_fmt;
+        (*frame).sample_rate = config.target_sample_rate as i32;
+        av_channel_layout_default(&mut (*frame).ch_layout, channels);
+        (*frame).nb_samples = take as i32;
+
+        if av_frame_get_buffer(frame, 0) < 0 {
+            return Err("Failed to allocate frame buffer".to_string());
+        }
+
+        let take_bytes = if self.planar {
+            take * self.bytes_per_sample
+        } else {
+            take * self.bytes_per_sample * channels as usize
+        };
+
+        let dst = (*frame).extended_data;
+        for (plane, buf) in self.pending.iter_mut().enumerate() {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), *dst.add(plane), take_bytes);
+            buf.drain(0..take_bytes);
+        }
+
+        (*frame).pts = self.pts;
+        self.pts += take as i64;
+
+        if avcodec_send_frame(self.enc_ctx.as_ptr(), frame) >= 0 {
+            self.drain_packets(out_pkt);
+        }
+
+        self.samples_written += take;
+        Ok(())
     }
 
-    (*out_stream).time_base = AVRational {
-        num: 1,
-        den: config.target_sample_rate as i32,
-    };
+    unsafe fn drain_packets(&mut self, out_pkt: &Packet) {
+        let pkt = out_pkt.as_ptr();
+        while avcodec_receive_packet(self.enc_ctx.as_ptr(), pkt) >= 0 {
+            av_packet_rescale_ts(pkt, (*self.enc_ctx.as_ptr()).time_base, (*self.stream).time_base);
+            (*pkt).stream_index = (*self.stream).index;
+            self.fmt_ctx.write_frame(pkt);
+            out_pkt.unref();
+        }
+    }
 
-    if ((*(*out_fmt_ctx).oformat).flags & AVFMT_NOFILE as i32) == 0 {
-        ret = avio_open(
-            &mut (*out_fmt_ctx).pb,
-            output_cstr.as_ptr(),
-            AVIO_FLAG_WRITE as i32,
-        );
-        if ret < 0 {
-            avcodec_free_context(&mut (enc_ctx as *mut _));
-            avformat_free_context(out_fmt_ctx);
-            avcodec_free_context(&mut (dec_ctx as *mut _));
-            return Err("Failed to open output file".to_string());
+    /// Buffers `planes` (one slice per plane, packed formats use a single
+    /// plane) and drains full encoder-sized frames as they accumulate,
+    /// carrying any remainder over to the next call.
+    unsafe fn write_samples(
+        &mut self,
+        planes: &[&[u8]],
+        channels: i32,
+        enc_frame: &Frame,
+        out_pkt: &Packet,
+        config: &ProcessorConfig,
+    ) -> Result<(), String> {
+        for (i, p) in planes.iter().enumerate() {
+            self.pending[i].extend_from_slice(p);
         }
+
+        let frame_size = if self.frame_size > 0 { self.frame_size } else { 1024 };
+
+        loop {
+            let pending_samples = self.samples_per_plane(channels, self.pending[0].len());
+            let take = if self.frame_size > 0 {
+                if pending_samples < frame_size {
+                    break;
+                }
+                frame_size
+            } else {
+                if pending_samples == 0 {
+                    break;
+                }
+                frame_size.min(pending_samples)
+            };
+
+            self.send_frame(take, channels, enc_frame, out_pkt, config)?;
+        }
+
+        Ok(())
     }
 
-    ret = avformat_write_header(out_fmt_ctx, ptr::null_mut());
-    if ret < 0 {
-        avcodec_free_context(&mut (enc_ctx as *mut _));
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to write header".to_string());
+    /// Emits whatever is left in the pending buffer as one final, possibly
+    /// shorter-than-`frame_size`, frame. Most encoders accept a short last
+    /// frame.
+    unsafe fn flush_pending(
+        &mut self,
+        channels: i32,
+        enc_frame: &Frame,
+        out_pkt: &Packet,
+        config: &ProcessorConfig,
+    ) -> Result<(), String> {
+        let take = self.samples_per_plane(channels, self.pending[0].len());
+        if take == 0 {
+            return Ok(());
+        }
+        self.send_frame(take, channels, enc_frame, out_pkt, config)
     }
 
-    // Resampler setup
-    let mut swr_ctx: *mut SwrContext = ptr::null_mut();
-    let mut dst_ch_layout = AVChannelLayout::default();
-    av_channel_layout_default(&mut dst_ch_layout, channels);
+    /// Flushes any pending samples, drains the encoder, and finalizes the
+    /// container (trailer + avio close happen in `OutputFormatContext`'s
+    /// `Drop` once `self` goes out of scope).
+    unsafe fn finish(
+        mut self,
+        channels: i32,
+        enc_frame: &Frame,
+        out_pkt: &Packet,
+        config: &ProcessorConfig,
+    ) -> Result<(), String> {
+        self.flush_pending(channels, enc_frame, out_pkt, config)?;
+        avcodec_send_frame(self.enc_ctx.as_ptr(), ptr::null());
+        self.drain_packets(out_pkt);
+        Ok(())
+    }
+}
 
-    ret = swr_alloc_set_opts2(
-        &mut swr_ctx,
-        &mut dst_ch_layout as *mut _,
-        AVSampleFormat_AV_SAMPLE_FMT_FLT,
-        config.target_sample_rate as i32,
-        &in_ch_layout as *const _ as *mut _,
-        in_sample_fmt,
-        in_sample_rate,
-        0,
-        ptr::null_mut(),
-    );
-    if ret < 0 || swr_ctx.is_null() {
-        avcodec_free_context(&mut (enc_ctx as *mut _));
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to alloc resampler".to_string());
+pub fn process_file(
+    input_path: &str,
+    output_path: &str,
+    config: &ProcessorConfig,
+) -> Result<ProcessOutcome, String> {
+    let input_cstr = CString::new(input_path).map_err(|e| e.to_string())?;
+    unsafe {
+        let (in_fmt_ctx, leftover) =
+            InputFormatContext::open(&input_cstr, config.io_timeout_usec, &config.extra_options)?;
+        process_file_inner(&in_fmt_ctx, output_path, config, &leftover)
     }
+}
 
-    av_opt_set_int(swr_ctx as *mut _, filter_size_cstr.as_ptr(), 64, 0);
-    av_opt_set_double(swr_ctx as *mut _, cutoff_cstr.as_ptr(), 0.97, 0);
+/// Same as [`process_file`], but demuxes from `source` (an in-memory buffer,
+/// a streaming channel, ...) instead of a path on disk.
+pub fn process_stream(
+    source: Box<dyn StreamSource>,
+    output_path: &str,
+    config: &ProcessorConfig,
+) -> Result<ProcessOutcome, String> {
+    unsafe {
+        let reader = AvioReader::new(source)?;
+        let (in_fmt_ctx, leftover) =
+            InputFormatContext::open_with_reader(reader, config.io_timeout_usec, &config.extra_options)?;
+        process_file_inner(&in_fmt_ctx, output_path, config, &leftover)
+    }
+}
 
-    ret = swr_init(swr_ctx);
-    if ret < 0 {
-        swr_free(&mut swr_ctx);
-        avcodec_free_context(&mut (enc_ctx as *mut _));
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to init resampler".to_string());
-    }
-
-    // Processing
-    let dec_frame = av_frame_alloc();
-    let enc_frame = av_frame_alloc();
-    let pkt = av_packet_alloc();
-    let out_pkt = av_packet_alloc();
-
-    if dec_frame.is_null() || enc_frame.is_null() || pkt.is_null() || out_pkt.is_null() {
-        av_frame_free(&mut (dec_frame as *mut _));
-        av_frame_free(&mut (enc_frame as *mut _));
-        av_packet_free(&mut (pkt as *mut _));
-        av_packet_free(&mut (out_pkt as *mut _));
-        swr_free(&mut swr_ctx);
-        avcodec_free_context(&mut (enc_ctx as *mut _));
-        avformat_free_context(out_fmt_ctx);
-        avcodec_free_context(&mut (dec_ctx as *mut _));
-        return Err("Failed to alloc frames/packets".to_string());
-    }
-
-    let max_samples = (config.max_duration_sec * config.target_sample_rate as f32) as usize;
-    let min_samples = (config.min_duration_sec * config.target_sample_rate as f32) as usize;
-    let mut total_output_samples: usize = 0;
-    let mut pts: i64 = 0;
+fn segment_output_path(output_path: &str, index: u32) -> CString {
+    let path = Path::new(output_path);
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("out");
+    let ext = path.extension().and_then(|s| s.to_str()).unwrap_or("wav");
+    let name = format!("{}_{:03}.{}", stem, index, ext);
 
-    let frame_size: usize = 1024;
-    let mut resample_buf = vec![0f32; 8192];
+    let full = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.join(name),
+        _ => PathBuf::from(name),
+    };
 
-    // Read and process packets
-    while av_read_frame(in_fmt_ctx, pkt) >= 0 {
-        if (*pkt).stream_index != stream_index {
-            av_packet_unref(pkt);
-            continue;
+    CString::new(full.to_str().unwrap()).unwrap()
+}
+
+/// Writes `available` resampled samples (laid out in `resample_buf` as
+/// `plane_count` planes of `plane_capacity` samples each) to `seg`, rolling
+/// over to the next numbered segment whenever `config.segment` is set and
+/// the current segment crosses `segment_samples`. In non-segment mode all
+/// `available` samples go to the single output; callers cap `available` at
+/// the room left before calling.
+#[allow(clippy::too_many_arguments)]
+unsafe fn emit_samples(
+    seg: &mut OutputSegment,
+    seg_index: &mut u32,
+    output_path: &str,
+    container_cstr: &CString,
+    encoder: *const AVCodec,
+    channels: i32,
+    enc_frame: &Frame,
+    out_pkt: &Packet,
+    config: &ProcessorConfig,
+    segment_samples: usize,
+    resample_buf: &[u8],
+    mut available: usize,
+) -> Result<(), String> {
+    let plane_count = seg.pending.len();
+    let plane_stride = resample_buf.len() / plane_count;
+    let mut src_offset: usize = 0;
+
+    loop {
+        let room = if config.segment {
+            segment_samples.saturating_sub(seg.samples_written)
+        } else {
+            available
+        };
+        let to_write = room.min(available);
+
+        if to_write > 0 {
+            let planes: Vec<&[u8]> = if plane_count == 1 {
+                let start = src_offset * seg.bytes_per_sample * channels as usize;
+                let len = to_write * seg.bytes_per_sample * channels as usize;
+                vec![&resample_buf[start..start + len]]
+            } else {
+                (0..plane_count)
+                    .map(|p| {
+                        let base = p * plane_stride + src_offset * seg.bytes_per_sample;
+                        let len = to_write * seg.bytes_per_sample;
+                        &resample_buf[base..base + len]
+                    })
+                    .collect()
+            };
+
+            seg.write_samples(&planes, channels, enc_frame, out_pkt, config)?;
+            src_offset += to_write;
+            available -= to_write;
         }
 
-        ret = avcodec_send_packet(dec_ctx, pkt);
-        av_packet_unref(pkt);
-        if ret < 0 {
-            continue;
+        if available == 0 {
+            break;
         }
 
-        while avcodec_receive_frame(dec_ctx, dec_frame) >= 0 {
-            if total_output_samples >= max_samples {
-                av_frame_unref(dec_frame);
-                break;
-            }
+        if !config.segment {
+            break;
+        }
+
+        // Roll over to the next numbered segment.
+        let finished = std::mem::replace(
+            seg,
+            OutputSegment::open(
+                &segment_output_path(output_path, *seg_index + 1),
+                container_cstr,
+                encoder,
+                channels,
+                config,
+            )?,
+        );
+        finished.finish(channels, enc_frame, out_pkt, config)?;
+        *seg_index += 1;
+    }
+
+    Ok(())
+}
+
+unsafe fn process_file_inner(
+    in_fmt_ctx: &InputFormatContext,
+    output_path: &str,
+    config: &ProcessorConfig,
+    format_leftover_options: &[(String, String)],
+) -> Result<ProcessOutcome, String> {
+    let (mut dec_ctx, stream_index, in_ch_layout, in_sample_fmt, in_sample_rate) =
+        open_decoder(in_fmt_ctx, format_leftover_options)?;
 
-            let in_nb_samples = (*dec_frame).nb_samples;
+    let channels = if in_ch_layout.nb_channels == 0 {
+        2
+    } else {
+        in_ch_layout.nb_channels
+    };
+
+    let codec_name_cstr = CString::new(config.output_codec.as_str()).map_err(|e| e.to_string())?;
+    let encoder = avcodec_find_encoder_by_name(codec_name_cstr.as_ptr());
+    if encoder.is_null() {
+        return Err(format!("Unknown output codec: {}", config.output_codec));
+    }
+
+    let (container_name, _ext) = container_for_codec(&config.output_codec);
+    let container_cstr = CString::new(container_name).unwrap();
+
+    let segment_samples = (config.max_duration_sec * config.target_sample_rate as f32) as usize;
+    let min_samples = (config.min_duration_sec * config.target_sample_rate as f32) as usize;
+
+    let mut seg_index: u32 = 0;
+    let first_output_cstr = if config.segment {
+        segment_output_path(output_path, seg_index)
+    } else {
+        CString::new(output_path).map_err(|e| e.to_string())?
+    };
+
+    let mut seg = OutputSegment::open(&first_output_cstr, &container_cstr, encoder, channels, config)?;
+
+    // Resampler setup: swr is configured to output directly in the
+    // encoder's negotiated sample format (packed or planar).
+    let mut dst_ch_layout = AVChannelLayout::default();
+    av_channel_layout_default(&mut dst_ch_layout, channels);
+    let resampler = Resampler::new(
+        &mut dst_ch_layout,
+        seg.sample_fmt,
+        config.target_sample_rate as i32,
+        &in_ch_layout,
+        in_sample_fmt,
+        in_sample_rate,
+    )?;
+
+    let filter_graph: Option<FilterGraph> = match &config.filter_chain {
+        Some(chain) => Some(build_filter_graph(chain, in_sample_rate, in_sample_fmt, &in_ch_layout)?),
+        None => None,
+    };
+
+    let dec_frame = Frame::new()?;
+    let filt_frame = Frame::new()?;
+    let enc_frame = Frame::new()?;
+    let pkt = Packet::new()?;
+    let out_pkt = Packet::new()?;
+
+    // Working buffer for resampled output: `plane_capacity` samples per
+    // channel, laid out as `plane_count` contiguous planes (1 for packed
+    // formats, `channels` for planar ones).
+    let plane_capacity: usize = 8192;
+    let plane_count = seg.pending.len();
+    let mut resample_buf = vec![0u8; plane_capacity * seg.bytes_per_sample * channels.max(1) as usize];
+
+    macro_rules! resample_and_emit {
+        ($frame:expr) => {{
+            let in_nb_samples = (*$frame.as_ptr()).nb_samples;
             let out_samples_est = av_rescale_rnd(
                 in_nb_samples as i64,
                 config.target_sample_rate as i64,
                 in_sample_rate as i64,
-                AVRounding_AV_ROUND_UP,
+                AV_ROUND_UP,
             ) as i32;
 
-            let mut out_ptr = resample_buf.as_mut_ptr() as *mut u8;
-            let in_ptr = (*dec_frame).extended_data;
-            let max_out = (resample_buf.len() / channels as usize) as i32;
+            let plane_stride = resample_buf.len() / plane_count;
+            let mut plane_ptrs: Vec<*mut u8> = (0..plane_count)
+                .map(|p| resample_buf.as_mut_ptr().add(p * plane_stride))
+                .collect();
+            let in_ptr = (*$frame.as_ptr()).extended_data;
 
             let converted = swr_convert(
-                swr_ctx,
-                &mut out_ptr as *mut *mut u8,
-                max_out.min(out_samples_est),
+                resampler.as_ptr(),
+                plane_ptrs.as_mut_ptr(),
+                (plane_capacity as i32).min(out_samples_est),
                 in_ptr as *mut *const u8,
                 in_nb_samples,
             );
 
-            av_frame_unref(dec_frame);
-            if converted <= 0 {
-                continue;
-            }
+            $frame.unref();
+            if converted > 0 {
+                let mut samples_to_write = converted as usize;
+                if !config.segment {
+                    let remaining = segment_samples.saturating_sub(seg.samples_written);
+                    if samples_to_write > remaining {
+                        samples_to_write = remaining;
+                    }
+                }
 
-            let mut samples_to_write = converted as usize;
-            let remaining = max_samples.saturating_sub(total_output_samples);
-            if samples_to_write > remaining {
-                samples_to_write = remaining;
+                emit_samples(
+                    &mut seg,
+                    &mut seg_index,
+                    output_path,
+                    &container_cstr,
+                    encoder,
+                    channels,
+                    &enc_frame,
+                    &out_pkt,
+                    config,
+                    segment_samples,
+                    &resample_buf,
+                    samples_to_write,
+                )?;
             }
+        }};
+    }
 
-            let mut offset: usize = 0;
-            while offset < samples_to_write {
-                let chunk = frame_size.min(samples_to_write - offset);
-
-                av_frame_unref(enc_frame);
-                (*enc_frame).format = AVSampleFormat_AV_SAMPLE_FMT_FLT;
-                (*enc_frame).sample_rate = config.target_sample_rate as i32;
-                av_channel_layout_default(&mut (*enc_frame).ch_layout, channels);
-                (*enc_frame).nb_samples = chunk as i32;
+    let mut truncated = false;
 
-                ret = av_frame_get_buffer(enc_frame, 0);
-                if ret < 0 {
-                    break;
-                }
+    // Read and process packets
+    'read: while av_read_frame(in_fmt_ctx.as_ptr(), pkt.as_ptr()) >= 0 {
+        if (*pkt.as_ptr()).stream_index != stream_index {
+            pkt.unref();
+            continue;
+        }
 
-                let src_start = offset * channels as usize;
-                let bytes = chunk * channels as usize * 4;
-                let dst = (*enc_frame).extended_data;
-                std::ptr::copy_nonoverlapping(
-                    resample_buf.as_ptr().add(src_start) as *const u8,
-                    *dst,
-                    bytes,
-                );
+        let send_ret = avcodec_send_packet(dec_ctx.as_ptr(), pkt.as_ptr());
+        pkt.unref();
+        if send_ret < 0 {
+            continue;
+        }
 
-                (*enc_frame).pts = pts;
-                pts += chunk as i64;
+        while avcodec_receive_frame(dec_ctx.as_ptr(), dec_frame.as_ptr()) >= 0 {
+            if !config.segment && seg.samples_written >= segment_samples {
+                dec_frame.unref();
+                truncated = true;
+                break 'read;
+            }
 
-                ret = avcodec_send_frame(enc_ctx, enc_frame);
-                if ret < 0 {
+            if let Some(fg) = &filter_graph {
+                if av_buffersrc_add_frame(fg.src_ctx, dec_frame.as_ptr()) < 0 {
+                    dec_frame.unref();
                     continue;
                 }
 
-                while avcodec_receive_packet(enc_ctx, out_pkt) >= 0 {
-                    av_packet_rescale_ts(out_pkt, (*enc_ctx).time_base, (*out_stream).time_base);
-                    (*out_pkt).stream_index = (*out_stream).index;
-                    av_interleaved_write_frame(out_fmt_ctx, out_pkt);
-                    av_packet_unref(out_pkt);
+                while av_buffersink_get_frame(fg.sink_ctx, filt_frame.as_ptr()) >= 0 {
+                    resample_and_emit!(filt_frame);
+                    if !config.segment && seg.samples_written >= segment_samples {
+                        truncated = true;
+                        break 'read;
+                    }
                 }
+            } else {
+                resample_and_emit!(dec_frame);
+            }
 
-                offset += chunk;
+            if !config.segment && seg.samples_written >= segment_samples {
+                truncated = true;
+                break 'read;
             }
+        }
+    }
 
-            total_output_samples += samples_to_write;
-            if total_output_samples >= max_samples {
+    // Flush the filter graph (push a null frame, drain whatever it still holds)
+    if let Some(fg) = &filter_graph {
+        av_buffersrc_add_frame(fg.src_ctx, ptr::null_mut());
+        while av_buffersink_get_frame(fg.sink_ctx, filt_frame.as_ptr()) >= 0 {
+            resample_and_emit!(filt_frame);
+            if !config.segment && seg.samples_written >= segment_samples {
                 break;
             }
         }
+    }
 
-        if total_output_samples >= max_samples {
+    // Flush resampler
+    loop {
+        if !config.segment && seg.samples_written >= segment_samples {
             break;
         }
-    }
 
-    // Flush resampler
-    while total_output_samples < max_samples {
-        let mut out_ptr = resample_buf.as_mut_ptr() as *mut u8;
-        let max_out = (resample_buf.len() / channels as usize) as i32;
-        let flushed = swr_convert(swr_ctx, &mut out_ptr as *mut *mut u8, max_out, ptr::null_mut(), 0);
+        let plane_stride = resample_buf.len() / plane_count;
+        let mut plane_ptrs: Vec<*mut u8> = (0..plane_count)
+            .map(|p| resample_buf.as_mut_ptr().add(p * plane_stride))
+            .collect();
+        let flushed = swr_convert(
+            resampler.as_ptr(),
+            plane_ptrs.as_mut_ptr(),
+            plane_capacity as i32,
+            ptr::null_mut(),
+            0,
+        );
         if flushed <= 0 {
             break;
         }
 
         let mut samples_to_write = flushed as usize;
-        let remaining = max_samples.saturating_sub(total_output_samples);
-        if samples_to_write > remaining {
-            samples_to_write = remaining;
-        }
-
-        let mut offset: usize = 0;
-        while offset < samples_to_write {
-            let chunk = frame_size.min(samples_to_write - offset);
-
-            av_frame_unref(enc_frame);
-            (*enc_frame).format = AVSampleFormat_AV_SAMPLE_FMT_FLT;
-            (*enc_frame).sample_rate = config.target_sample_rate as i32;
-            av_channel_layout_default(&mut (*enc_frame).ch_layout, channels);
-            (*enc_frame).nb_samples = chunk as i32;
-
-            ret = av_frame_get_buffer(enc_frame, 0);
-            if ret < 0 {
-                break;
-            }
-
-            let src_start = offset * channels as usize;
-            let bytes = chunk * channels as usize * 4;
-            let dst = (*enc_frame).extended_data;
-            std::ptr::copy_nonoverlapping(
-                resample_buf.as_ptr().add(src_start) as *const u8,
-                *dst,
-                bytes,
-            );
-
-            (*enc_frame).pts = pts;
-            pts += chunk as i64;
-
-            avcodec_send_frame(enc_ctx, enc_frame);
-            while avcodec_receive_packet(enc_ctx, out_pkt) >= 0 {
-                av_packet_rescale_ts(out_pkt, (*enc_ctx).time_base, (*out_stream).time_base);
-                (*out_pkt).stream_index = (*out_stream).index;
-                av_interleaved_write_frame(out_fmt_ctx, out_pkt);
-                av_packet_unref(out_pkt);
+        if !config.segment {
+            let remaining = segment_samples.saturating_sub(seg.samples_written);
+            if samples_to_write > remaining {
+                samples_to_write = remaining;
             }
-
-            offset += chunk;
         }
 
-        total_output_samples += samples_to_write;
+        emit_samples(
+            &mut seg,
+            &mut seg_index,
+            output_path,
+            &container_cstr,
+            encoder,
+            channels,
+            &enc_frame,
+            &out_pkt,
+            config,
+            segment_samples,
+            &resample_buf,
+            samples_to_write,
+        )?;
     }
 
-    // Pad with silence if needed
-    if total_output_samples < min_samples {
-        resample_buf.fill(0.0);
-        let mut silence_remaining = min_samples - total_output_samples;
+    // Pad the final (possibly short) segment with silence if needed
+    let padded = seg.samples_written < min_samples;
+    if padded {
+        resample_buf.fill(0);
+        let mut silence_remaining = min_samples - seg.samples_written;
 
         while silence_remaining > 0 {
-            let chunk = frame_size.min(silence_remaining);
-
-            av_frame_unref(enc_frame);
-            (*enc_frame).format = AVSampleFormat_AV_SAMPLE_FMT_FLT;
-            (*enc_frame).sample_rate = config.target_sample_rate as i32;
-            av_channel_layout_default(&mut (*enc_frame).ch_layout, channels);
-            (*enc_frame).nb_samples = chunk as i32;
-
-            ret = av_frame_get_buffer(enc_frame, 0);
-            if ret < 0 {
-                break;
-            }
-
-            let bytes = chunk * channels as usize * 4;
-            let dst = (*enc_frame).extended_data;
-            std::ptr::write_bytes(*dst, 0, bytes);
-
-            (*enc_frame).pts = pts;
-            pts += chunk as i64;
-
-            avcodec_send_frame(enc_ctx, enc_frame);
-            while avcodec_receive_packet(enc_ctx, out_pkt) >= 0 {
-                av_packet_rescale_ts(out_pkt, (*enc_ctx).time_base, (*out_stream).time_base);
-                (*out_pkt).stream_index = (*out_stream).index;
-                av_interleaved_write_frame(out_fmt_ctx, out_pkt);
-                av_packet_unref(out_pkt);
-            }
-
+            let chunk = silence_remaining.min(plane_capacity);
+            emit_samples(
+                &mut seg,
+                &mut seg_index,
+                output_path,
+                &container_cstr,
+                encoder,
+                channels,
+                &enc_frame,
+                &out_pkt,
+                config,
+                segment_samples,
+                &resample_buf,
+                chunk,
+            )?;
             silence_remaining -= chunk;
         }
     }
 
-    // Flush encoder
-    avcodec_send_frame(enc_ctx, ptr::null());
-    while avcodec_receive_packet(enc_ctx, out_pkt) >= 0 {
-        av_packet_rescale_ts(out_pkt, (*enc_ctx).time_base, (*out_stream).time_base);
-        (*out_pkt).stream_index = (*out_stream).index;
-        av_interleaved_write_frame(out_fmt_ctx, out_pkt);
-        av_packet_unref(out_pkt);
-    }
-
-    av_write_trailer(out_fmt_ctx);
-
-    // Cleanup
-    av_frame_free(&mut (dec_frame as *mut _));
-    av_frame_free(&mut (enc_frame as *mut _));
-    av_packet_free(&mut (pkt as *mut _));
-    av_packet_free(&mut (out_pkt as *mut _));
-    swr_free(&mut swr_ctx);
-    avcodec_free_context(&mut (enc_ctx as *mut _));
+    let sample_rate = config.target_sample_rate as f32;
+    let outputs: Vec<OutputFile> = if config.segment {
+        let mut outputs: Vec<OutputFile> = (0..seg_index)
+            .map(|i| OutputFile {
+                path: segment_output_path(output_path, i).to_str().unwrap().to_string(),
+                duration_sec: segment_samples as f32 / sample_rate,
+                truncated: false,
+                padded: false,
+            })
+            .collect();
+        outputs.push(OutputFile {
+            path: segment_output_path(output_path, seg_index).to_str().unwrap().to_string(),
+            duration_sec: seg.samples_written as f32 / sample_rate,
+            truncated: false,
+            padded,
+        });
+        outputs
+    } else {
+        vec![OutputFile {
+            path: output_path.to_string(),
+            duration_sec: seg.samples_written as f32 / sample_rate,
+            truncated,
+            padded,
+        }]
+    };
 
-    if ((*(*out_fmt_ctx).oformat).flags & AVFMT_NOFILE as i32) == 0 {
-        avio_closep(&mut (*out_fmt_ctx).pb);
-    }
-    avformat_free_context(out_fmt_ctx);
-    avcodec_free_context(&mut (dec_ctx as *mut _));
+    seg.finish(channels, &enc_frame, &out_pkt, config)?;
 
-    Ok(())
+    Ok(ProcessOutcome {
+        input_sample_rate: in_sample_rate as u32,
+        outputs,
+    })
 }